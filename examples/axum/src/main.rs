@@ -39,13 +39,13 @@ async fn main() {
     ip_service.add_ip(
         std::net::IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
         "Test".to_string(),
-        "2021-10-15".to_string(),
+        "2021-10-15".parse().unwrap(),
     ).await;
 
     let app = Router::new().route("/", get(handler)).layer(
         ServiceBuilder::new()
             .layer(TraceLayer::new_for_http())
-            .layer(AddConnectionInfoLayer)
+            .layer(AddConnectionInfoLayer::new())
             .layer(FilterLayer::new(Arc::new(geo_service)))
             .into_inner(),
     );
@@ -0,0 +1,157 @@
+use std::{
+    net::IpAddr,
+    task::{Context, Poll},
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    body::IpResponseBody,
+    geo_filter::IpAddrExt,
+    network_filter_service::{FilterDecision, NetworkFilter},
+    types::Mode,
+};
+
+/// Wraps a filter with a set of trusted addresses that are never blocked,
+/// whatever the wrapped filter or its [`Mode`] decides,
+/// e.g. a country blacklist that always lets the office address through.
+#[derive(Debug, Clone)]
+pub struct Allowlisted<F> {
+    inner: F,
+    allowlist: DashMap<IpAddr, ()>,
+}
+
+impl<F: NetworkFilter> Allowlisted<F> {
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+            allowlist: DashMap::new(),
+        }
+    }
+
+    /// The wrapped filter, e.g. to update its countries or entries.
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn add_allow(&self, ip: IpAddr) {
+        self.allowlist.insert(ip, ());
+    }
+
+    pub fn remove_allow(&self, ip: IpAddr) {
+        self.allowlist.remove(&ip);
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.allowlist.contains_key(ip)
+    }
+}
+
+impl<F: NetworkFilter> NetworkFilter for Allowlisted<F> {
+    async fn block(&self, ip: impl IpAddrExt, network: bool) {
+        self.inner.block(ip, network).await
+    }
+
+    async fn unblock(&self, ip: impl IpAddrExt, network: bool) {
+        self.inner.unblock(ip, network).await
+    }
+
+    async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+        self.decide(ip).await.blocked
+    }
+
+    async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+        let ip = ip.to_ip_addr();
+        if self.is_allowed(&ip) {
+            return FilterDecision::new(false).with_reason("allowlist");
+        }
+        self.inner.decide(ip).await
+    }
+
+    fn decide_now(&self, ip: IpAddr) -> Option<FilterDecision> {
+        if self.is_allowed(&ip) {
+            return Some(FilterDecision::new(false).with_reason("allowlist"));
+        }
+        self.inner.decide_now(ip)
+    }
+
+    /// Requests from allowlisted addresses aren't counted, so they can't
+    /// auto-ban a trusted address in the wrapped filter.
+    fn record_request(&self, ip: IpAddr) {
+        if !self.is_allowed(&ip) {
+            self.inner.record_request(ip);
+        }
+    }
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
+        self.inner.to_denied_response()
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        self.inner.mode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{geo_filter::GeoIpv4Filter, ip_filter::DualIpFilter, types::CountryLocation};
+    use std::{net::Ipv4Addr, time::Duration};
+
+    #[tokio::test]
+    async fn test_allowlist_overrides_country() {
+        let networks = DashMap::new();
+        networks.insert(
+            "203.0.113.0/24".parse().unwrap(),
+            CountryLocation {
+                geoname_id: 1,
+                locale_code: "en".to_string(),
+                continent_code: "EU".to_string(),
+                continent_name: "Europe".to_string(),
+                country_iso_code: Some("FR".to_string()),
+                country_name: Some("France".to_string()),
+                is_in_european_union: true,
+            },
+        );
+        let filter = Allowlisted::new(GeoIpv4Filter::from_networks(Mode::BlackList, networks));
+        filter.inner().set_countries(vec!["France".to_string()]);
+
+        let trusted = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5));
+        let other = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 6));
+        filter.add_allow(trusted);
+
+        assert!(!filter.is_blocked(trusted).await);
+        assert_eq!(
+            filter.decide(trusted).await.reason.as_deref(),
+            Some("allowlist")
+        );
+        assert!(filter.is_blocked(other).await);
+
+        filter.remove_allow(trusted);
+        assert!(filter.is_blocked(trusted).await);
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_forwards_auto_ban() {
+        let filter = Allowlisted::new(DualIpFilter::new(Mode::BlackList).enable_auto_ban(
+            1,
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+        ));
+        let trusted = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5));
+        let other = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 6));
+        filter.add_allow(trusted);
+
+        for ip in [trusted, other] {
+            filter.record_request(ip);
+            filter.record_request(ip);
+        }
+        assert!(filter.is_blocked(other).await);
+        assert!(!filter.is_blocked(trusted).await);
+        assert_eq!(filter.inner().blocked_count(), (1, 0));
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use dashmap::DashMap;
+use ipnetwork::Ipv4Network;
+use tracing::info;
+
+use crate::{
+    body::{create_network_denied_response, IpResponseBody},
+    compress::{cache_file, load_cached},
+    error::GeoLoadError,
+    extract::extract_and_parse_asn_csv,
+    geo_filter::IpAddrExt,
+    ip_filter::prefix_group,
+    network_filter_service::{FilterDecision, NetworkFilter},
+    types::{AsnData, Mode},
+};
+
+/// The autonomous system a network is announced by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsnInfo {
+    pub number: u32,
+    pub organization: Option<String>,
+}
+
+/// Filters requests by the autonomous system (ASN) their address belongs to,
+/// using the GeoLite2-ASN dataset.
+///
+/// Cloning is cheap: clones share the loaded network data.
+#[derive(Debug, Clone)]
+pub struct AsnFilter {
+    networks: Arc<AsnNetworks>,
+    pub asns: DashMap<u32, bool>,
+    pub mode: Mode,
+}
+
+impl AsnFilter {
+    /// Loads the networks from a `GeoLite2-ASN-CSV` zip archive, caching the
+    /// parsed data next to the working directory.
+    pub fn new(mode: Mode, path_to_data: impl Into<PathBuf>) -> Result<Self, GeoLoadError> {
+        let data_path = cache_file("asn_data");
+
+        let asn_data = load_cached(&path_to_data.into(), &data_path, extract_and_parse_asn_csv)?;
+
+        Ok(Self::with_networks(mode, build_networks(asn_data)))
+    }
+
+    /// Creates a filter over networks that were already resolved to ASNs.
+    pub fn from_networks(mode: Mode, networks: DashMap<Ipv4Network, AsnInfo>) -> Self {
+        Self::with_networks(mode, networks.into_iter().collect())
+    }
+
+    fn with_networks(mode: Mode, networks: AsnNetworks) -> Self {
+        Self {
+            networks: Arc::new(networks),
+            asns: DashMap::new(),
+            mode,
+        }
+    }
+
+    pub fn set_blocked_asns(&self, asns: Vec<u32>) {
+        self.asns.clear();
+        tracing::info!("Setting ASNs: {:?}, mode: {}", asns, self.mode);
+        for asn in asns {
+            self.asns.insert(asn, true);
+        }
+    }
+
+    /// Returns the system announcing the most specific network containing `ip`.
+    pub fn get_asn_for_ip(&self, ip: &Ipv4Addr) -> Option<AsnInfo> {
+        self.networks.longest_match(*ip).cloned()
+    }
+
+    pub fn is_asn_blocked(&self, asn: u32) -> bool {
+        match self.mode {
+            Mode::BlackList => self.asns.contains_key(&asn),
+            Mode::WhiteList => !self.asns.contains_key(&asn),
+        }
+    }
+
+    pub async fn is_ip_blocked(&self, ip: &Ipv4Addr) -> bool {
+        self.decide_ip(ip).blocked
+    }
+
+    /// Decides on `ip`, IPv6 addresses belong to no known system.
+    fn decide_addr(&self, ip: IpAddr) -> FilterDecision {
+        match ip {
+            IpAddr::V4(ip) => self.decide_ip(&ip),
+            _ => FilterDecision::new(false),
+        }
+    }
+
+    fn decide_ip(&self, ip: &Ipv4Addr) -> FilterDecision {
+        match self.get_asn_for_ip(ip) {
+            Some(asn) => {
+                let mut decision = FilterDecision::new(self.is_asn_blocked(asn.number));
+                if self.asns.contains_key(&asn.number) {
+                    decision = decision.with_reason(match &asn.organization {
+                        Some(organization) => format!("ASN {} ({})", asn.number, organization),
+                        None => format!("ASN {}", asn.number),
+                    });
+                }
+                decision
+            }
+            None => FilterDecision::new(false),
+        }
+    }
+
+    /// Adds the ASN of `ip` to the list in blacklist mode, or removes it in whitelist mode.
+    fn set_listed(&self, ip: IpAddr, blocked: bool) {
+        let IpAddr::V4(ip) = ip else {
+            return;
+        };
+        if let Some(asn) = self.get_asn_for_ip(&ip) {
+            if blocked == matches!(self.mode, Mode::BlackList) {
+                self.asns.insert(asn.number, true);
+            } else {
+                self.asns.remove(&asn.number);
+            }
+        }
+    }
+}
+
+/// ASN networks grouped by prefix length, longest prefix first, so a lookup
+/// costs one hash probe per distinct prefix length instead of a scan.
+#[derive(Debug, Default)]
+struct AsnNetworks(Vec<(u8, HashMap<u32, AsnInfo>)>);
+
+impl AsnNetworks {
+    fn insert(&mut self, network: Ipv4Network, asn: AsnInfo) {
+        prefix_group(&mut self.0, network.prefix()).insert(network.network().into(), asn);
+    }
+
+    fn longest_match(&self, ip: Ipv4Addr) -> Option<&AsnInfo> {
+        let ip = u32::from(ip);
+        self.0.iter().find_map(|(prefix, networks)| {
+            let network = ip & u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+            networks.get(&network)
+        })
+    }
+}
+
+impl FromIterator<(Ipv4Network, AsnInfo)> for AsnNetworks {
+    fn from_iter<I: IntoIterator<Item = (Ipv4Network, AsnInfo)>>(iter: I) -> Self {
+        let mut networks = Self::default();
+        for (network, asn) in iter {
+            networks.insert(network, asn);
+        }
+        networks
+    }
+}
+
+fn build_networks(asn_data: AsnData) -> AsnNetworks {
+    info!("Loaded {} ASN blocks", asn_data.blocks.len());
+
+    asn_data
+        .blocks
+        .into_iter()
+        .filter_map(|block| {
+            let network = block.network.parse().ok()?;
+            let asn = AsnInfo {
+                number: block.autonomous_system_number?,
+                organization: block.autonomous_system_organization,
+            };
+            Some((network, asn))
+        })
+        .collect()
+}
+
+impl NetworkFilter for AsnFilter {
+    /// Blocks the whole autonomous system `ip` belongs to.
+    async fn block(&self, ip: impl IpAddrExt, _network: bool) {
+        self.set_listed(ip.to_ip_addr(), true);
+    }
+
+    /// Unblocks the whole autonomous system `ip` belongs to.
+    async fn unblock(&self, ip: impl IpAddrExt, _network: bool) {
+        self.set_listed(ip.to_ip_addr(), false);
+    }
+
+    async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+        match ip.to_ip_addr() {
+            IpAddr::V4(ip) => self.is_ip_blocked(&ip).await,
+            _ => false,
+        }
+    }
+
+    async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+        self.decide_addr(ip.to_ip_addr())
+    }
+
+    fn decide_now(&self, ip: IpAddr) -> Option<FilterDecision> {
+        Some(self.decide_addr(ip))
+    }
+
+    fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
+        create_network_denied_response()
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        Some(self.mode.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::tests::write_archive;
+
+    const ASN_BLOCKS: &str = "network,autonomous_system_number,autonomous_system_organization
+203.0.113.0/24,64500,\"Example Hosting\"
+198.51.100.0/24,64501,\"Example Telecom\"
+";
+
+    #[tokio::test]
+    async fn test_asn_filter_from_archive() {
+        let path = write_archive(
+            "asn",
+            &[(
+                "GeoLite2-ASN-CSV_20241015/GeoLite2-ASN-Blocks-IPv4.csv",
+                ASN_BLOCKS,
+            )],
+        );
+        let asn_data = extract_and_parse_asn_csv(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let filter = AsnFilter::with_networks(Mode::BlackList, build_networks(asn_data));
+        filter.set_blocked_asns(vec![64500]);
+
+        let hosting = Ipv4Addr::new(203, 0, 113, 9);
+        let telecom = Ipv4Addr::new(198, 51, 100, 9);
+        assert_eq!(filter.get_asn_for_ip(&hosting).unwrap().number, 64500);
+        assert!(filter.is_blocked(hosting).await);
+        assert!(!filter.is_blocked(telecom).await);
+        assert!(!filter.is_blocked(Ipv4Addr::new(192, 0, 2, 1)).await);
+        assert_eq!(
+            filter.decide(hosting).await.reason.as_deref(),
+            Some("ASN 64500 (Example Hosting)")
+        );
+
+        filter.block(telecom, false).await;
+        assert!(filter.is_blocked(telecom).await);
+        filter.unblock(hosting, false).await;
+        assert!(!filter.is_blocked(hosting).await);
+    }
+
+    #[tokio::test]
+    async fn test_asn_filter_whitelist() {
+        let networks = DashMap::new();
+        networks.insert(
+            "203.0.113.0/24".parse().unwrap(),
+            AsnInfo {
+                number: 64500,
+                organization: None,
+            },
+        );
+        let filter = AsnFilter::from_networks(Mode::WhiteList, networks);
+        filter.set_blocked_asns(vec![64500]);
+
+        assert!(!filter.is_blocked(Ipv4Addr::new(203, 0, 113, 9)).await);
+        filter.block(Ipv4Addr::new(203, 0, 113, 9), false).await;
+        assert!(filter.is_blocked(Ipv4Addr::new(203, 0, 113, 9)).await);
+    }
+
+    #[test]
+    fn test_asn_longest_prefix() {
+        let networks = DashMap::new();
+        let asn = |number| AsnInfo {
+            number,
+            organization: None,
+        };
+        networks.insert("203.0.0.0/8".parse().unwrap(), asn(64500));
+        networks.insert("203.0.113.0/24".parse().unwrap(), asn(64501));
+        networks.insert("203.0.113.128/25".parse().unwrap(), asn(64502));
+        let filter = AsnFilter::from_networks(Mode::BlackList, networks);
+
+        let number = |ip| filter.get_asn_for_ip(&ip).map(|asn| asn.number);
+        assert_eq!(number(Ipv4Addr::new(203, 0, 113, 200)), Some(64502));
+        assert_eq!(number(Ipv4Addr::new(203, 0, 113, 9)), Some(64501));
+        assert_eq!(number(Ipv4Addr::new(203, 1, 2, 3)), Some(64500));
+        assert_eq!(number(Ipv4Addr::new(198, 51, 100, 1)), None);
+    }
+}
@@ -5,8 +5,15 @@ use http_body_util::Full;
 use pin_project_lite::pin_project;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::SystemTime;
 
 pin_project! {
+    /// The response body of a filtered service: either a synthetic denial or
+    /// the inner service's body.
+    ///
+    /// An inner body is forwarded untouched, including its trailers,
+    /// [`Body::size_hint`] and [`Body::is_end_stream`]. Use
+    /// [`IpResponseBody::into_inner`] to get it back.
     pub struct IpResponseBody<B> {
         #[pin]
         inner: IpResponseBodyInner<B>
@@ -30,6 +37,14 @@ impl<B> IpResponseBody<B> {
         }
     }
 
+    fn network_denied() -> Self {
+        Self {
+            inner: IpResponseBodyInner::AccessDenied {
+                body: Full::from(ACCESS_DENIED_NETWORK_BODY),
+            },
+        }
+    }
+
     fn ip_not_found() -> Self {
         Self {
             inner: IpResponseBodyInner::AccessDenied {
@@ -38,11 +53,71 @@ impl<B> IpResponseBody<B> {
         }
     }
 
+    fn ip_not_identified() -> Self {
+        Self {
+            inner: IpResponseBodyInner::AccessDenied {
+                body: Full::from(IP_NOT_IDENTIFIED_BODY),
+            },
+        }
+    }
+
+    /// Wraps a body built outside the crate, e.g. by a custom response factory.
+    pub(crate) fn from_bytes(body: Bytes) -> Self {
+        Self {
+            inner: IpResponseBodyInner::AccessDenied {
+                body: Full::new(body),
+            },
+        }
+    }
+
+    fn json(body: String) -> Self {
+        Self {
+            inner: IpResponseBodyInner::AccessDenied {
+                body: Full::from(body),
+            },
+        }
+    }
+
     pub(crate) fn new(body: B) -> Self {
         Self {
             inner: IpResponseBodyInner::Body { body },
         }
     }
+
+    /// Returns the inner service's body, or the denial body unchanged as the error.
+    pub fn into_inner(self) -> Result<B, Self> {
+        match self.inner {
+            IpResponseBodyInner::Body { body } => Ok(body),
+            inner => Err(Self { inner }),
+        }
+    }
+}
+
+/// A body type with no values, for responses that can only be denials.
+pub enum NoBody {}
+
+impl Body for NoBody {
+    type Data = Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        match *self {}
+    }
+}
+
+impl IpResponseBody<NoBody> {
+    /// Retypes a denial body for use with any inner body type.
+    pub(crate) fn into_body<B>(self) -> IpResponseBody<B> {
+        match self.inner {
+            IpResponseBodyInner::AccessDenied { body } => IpResponseBody {
+                inner: IpResponseBodyInner::AccessDenied { body },
+            },
+            IpResponseBodyInner::Body { body } => match body {},
+        }
+    }
 }
 
 pin_project! {
@@ -93,7 +168,9 @@ where
 
 const ACCESS_DENIED_GEO_BODY: &[u8] = b"Access denied based on country of origin";
 const ACCESS_DENIED_IP_BODY: &[u8] = b"Access denied based on IP address";
+const ACCESS_DENIED_NETWORK_BODY: &[u8] = b"Access denied based on network";
 const ACCESS_DENIED_NOT_FOUND_BODY: &[u8] = b"Access denied IP not found";
+const IP_NOT_IDENTIFIED_BODY: &[u8] = b"Could not determine the client IP address";
 
 pub fn create_geo_access_denied_response<B>() -> Response<IpResponseBody<B>>
 where
@@ -121,6 +198,21 @@ where
     res
 }
 
+/// A `400 Bad Request` for a request whose client address couldn't be
+/// determined, as opposed to one that was denied.
+pub fn create_ip_not_identified_response<B>() -> Response<IpResponseBody<B>>
+where
+    B: Body,
+{
+    let mut res = Response::new(IpResponseBody::ip_not_identified());
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    res.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    res
+}
+
 pub fn create_ip_address_denied_response<B>() -> Response<IpResponseBody<B>>
 where
     B: Body,
@@ -133,3 +225,154 @@ where
     );
     res
 }
+
+pub fn create_network_denied_response<B>() -> Response<IpResponseBody<B>>
+where
+    B: Body,
+{
+    let mut res = Response::new(IpResponseBody::network_denied());
+    *res.status_mut() = StatusCode::FORBIDDEN;
+    res.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    res
+}
+
+/// Turns a denial into `429 Too Many Requests` for a block lifted at `expires`,
+/// telling the client when to retry with a `Retry-After` header.
+pub fn with_retry_after<B>(mut res: Response<B>, expires: SystemTime) -> Response<B> {
+    let remaining = expires
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    // Round up so the client never retries before the block is lifted.
+    let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+    *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    res.headers_mut()
+        .insert(http::header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+    res
+}
+
+/// A JSON denial, `{"error":"access_denied","reason":"country","detail":"US"}`.
+///
+/// `reason` names what the decision was based on and `detail` the matching
+/// value, e.g. the country code; `detail` is `null` when `None`.
+pub fn create_access_denied_response_json<B>(
+    reason: &str,
+    detail: Option<&str>,
+) -> Response<IpResponseBody<B>>
+where
+    B: Body,
+{
+    let detail = detail.map_or_else(|| "null".to_string(), json_string);
+    let body = format!(
+        r#"{{"error":"access_denied","reason":{},"detail":{}}}"#,
+        json_string(reason),
+        detail
+    );
+    json_response(body)
+}
+
+/// The JSON counterpart of [`create_ip_not_found_response`], `{"error":"ip_not_found"}`.
+pub fn create_ip_not_found_response_json<B>() -> Response<IpResponseBody<B>>
+where
+    B: Body,
+{
+    json_response(r#"{"error":"ip_not_found"}"#.to_string())
+}
+
+/// The JSON counterpart of [`create_ip_not_identified_response`],
+/// `{"error":"ip_not_identified"}`.
+pub fn create_ip_not_identified_response_json<B>() -> Response<IpResponseBody<B>>
+where
+    B: Body,
+{
+    let mut res = json_response(r#"{"error":"ip_not_identified"}"#.to_string());
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    res
+}
+
+fn json_response<B>(body: String) -> Response<IpResponseBody<B>> {
+    let mut res = Response::new(IpResponseBody::json(body));
+    *res.status_mut() = StatusCode::FORBIDDEN;
+    res.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+/// Quotes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn body_json(res: Response<IpResponseBody<NoBody>>) -> serde_json::Value {
+        let bytes = res.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_forwards_inner_body() {
+        let body = IpResponseBody::new(Full::new(Bytes::from_static(b"hello")));
+        assert_eq!(body.size_hint().exact(), Some(5));
+        assert!(!body.is_end_stream());
+        let Ok(inner) = body.into_inner() else {
+            panic!("expected the inner body");
+        };
+        assert_eq!(inner.collect().await.unwrap().to_bytes(), "hello");
+
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let frames = futures_lite::stream::iter([
+            Ok::<_, std::convert::Infallible>(http_body::Frame::data(Bytes::from_static(b"hi"))),
+            Ok(http_body::Frame::trailers(trailers.clone())),
+        ]);
+        let body = IpResponseBody::new(http_body_util::StreamBody::new(frames));
+        let collected = body.collect().await.unwrap();
+        assert_eq!(collected.trailers(), Some(&trailers));
+        assert_eq!(collected.to_bytes(), "hi");
+
+        let denied = create_ip_not_found_response::<NoBody>().into_body();
+        assert!(denied.into_inner().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_json_denial() {
+        let res = create_access_denied_response_json::<NoBody>("country", Some("US"));
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            res.headers()[http::header::CONTENT_TYPE],
+            "application/json"
+        );
+        assert_eq!(
+            body_json(res).await,
+            serde_json::json!({"error": "access_denied", "reason": "country", "detail": "US"})
+        );
+
+        let res = create_access_denied_response_json::<NoBody>("ip", Some("say \"hi\"\\\n"));
+        assert_eq!(body_json(res).await["detail"], "say \"hi\"\\\n");
+
+        let res = create_access_denied_response_json::<NoBody>("ip", None);
+        assert!(body_json(res).await["detail"].is_null());
+    }
+}
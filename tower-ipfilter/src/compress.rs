@@ -1,25 +1,362 @@
-use std::io::BufReader;
-use std::{error::Error, fs::File, io::BufWriter, path::Path};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use std::{fs::File, io::BufWriter, path::Path};
+use bincode::{Decode, Encode};
+use tracing::warn;
 use flate2::write::GzEncoder;
-use flate2::read::GzDecoder;
 use flate2::Compression;
-use crate::types::GeoData;
+use crate::error::GeoLoadError;
 
 const BINCODE_CONFIG : bincode::config::Configuration = bincode::config::standard();
 
-pub fn save_compressed_data(data: &GeoData, path: &Path) -> Result<(), Box<dyn Error>> {
+/// Bumped whenever the encoded layout of the cached data changes.
+const CACHE_FORMAT_VERSION: u32 = 3;
+
+/// Written ahead of the data so a cache built by an older version or from a
+/// different source archive is detected as stale.
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct CacheHeader {
+    version: u32,
+    source_len: u64,
+    source_modified: u64,
+}
+
+impl CacheHeader {
+    pub fn for_source(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let source_modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|modified| modified.as_secs())
+            .unwrap_or_default();
+        Ok(Self {
+            version: CACHE_FORMAT_VERSION,
+            source_len: metadata.len(),
+            source_modified,
+        })
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of the cache file.
+///
+/// New caches use zstd when the `zstd` feature is enabled and gzip otherwise.
+/// Loading recognizes the format by its magic bytes, so a gzip cache stays
+/// readable after switching.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Codec {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Codec {
+    #[cfg(not(feature = "zstd"))]
+    const DEFAULT: Codec = Codec::Gzip;
+    #[cfg(feature = "zstd")]
+    const DEFAULT: Codec = Codec::Zstd;
+
+    /// Every codec caches can be loaded with, the default first.
+    #[cfg(not(feature = "zstd"))]
+    const ALL: &'static [Codec] = &[Codec::Gzip];
+    #[cfg(feature = "zstd")]
+    const ALL: &'static [Codec] = &[Codec::Zstd, Codec::Gzip];
+
+    fn extension(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gz",
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => "zst",
+        }
+    }
+
+    fn detect(magic: &[u8]) -> io::Result<Codec> {
+        if magic.starts_with(GZIP_MAGIC) {
+            Ok(Codec::Gzip)
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            #[cfg(feature = "zstd")]
+            return Ok(Codec::Zstd);
+            #[cfg(not(feature = "zstd"))]
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "cache is zstd compressed but the zstd feature is disabled",
+            ));
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "cache is not compressed"))
+        }
+    }
+
+    fn encoder(self, file: File) -> io::Result<CacheEncoder> {
+        match self {
+            Codec::Gzip => Ok(CacheEncoder::Gzip(GzEncoder::new(file, Compression::default()))),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(CacheEncoder::Zstd(zstd::Encoder::new(file, 0)?)),
+        }
+    }
+
+    fn decoder<R: BufRead>(self, reader: R) -> io::Result<CacheDecoder<R>> {
+        match self {
+            Codec::Gzip => Ok(CacheDecoder::Gzip(flate2::bufread::GzDecoder::new(reader))),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(CacheDecoder::Zstd(zstd::Decoder::with_buffer(reader)?)),
+        }
+    }
+}
+
+enum CacheDecoder<R: BufRead> {
+    Gzip(flate2::bufread::GzDecoder<R>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Decoder<'static, R>),
+}
+
+impl<R: BufRead> Read for CacheDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            CacheDecoder::Gzip(decoder) => decoder.read(buf),
+            #[cfg(feature = "zstd")]
+            CacheDecoder::Zstd(decoder) => decoder.read(buf),
+        }
+    }
+}
+
+enum CacheEncoder {
+    Gzip(GzEncoder<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl CacheEncoder {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            CacheEncoder::Gzip(encoder) => encoder.finish().map(drop),
+            #[cfg(feature = "zstd")]
+            CacheEncoder::Zstd(encoder) => encoder.finish().map(drop),
+        }
+    }
+}
+
+impl Write for CacheEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CacheEncoder::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            CacheEncoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CacheEncoder::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            CacheEncoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// The cache file for `stem` in the working directory, e.g. `geo_ip_data.bin.gz`.
+///
+/// This is the file for the default codec, unless only a cache with another
+/// readable codec exists, e.g. a gzip cache written before enabling `zstd`.
+pub fn cache_file(stem: &str) -> PathBuf {
+    cache_file_in(Path::new(""), stem)
+}
+
+fn cache_file_in(dir: &Path, stem: &str) -> PathBuf {
+    let path = |codec: Codec| dir.join(format!("{}.bin.{}", stem, codec.extension()));
+    Codec::ALL
+        .iter()
+        .map(|codec| path(*codec))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| path(Codec::DEFAULT))
+}
+
+pub fn save_compressed_data<T: Encode>(data: &T, header: &CacheHeader, path: &Path) -> Result<(), GeoLoadError> {
+    save_with(Codec::DEFAULT, data, header, path)
+}
+
+fn save_with<T: Encode>(codec: Codec, data: &T, header: &CacheHeader, path: &Path) -> Result<(), GeoLoadError> {
     let file = File::create(path)?;
-    let encoder = GzEncoder::new(file, Compression::default());
-    let mut writer = BufWriter::new(encoder);
-    
+    let mut writer = BufWriter::new(codec.encoder(file)?);
+
+    bincode::encode_into_std_write(header, &mut writer, BINCODE_CONFIG)?;
     bincode::encode_into_std_write(data, &mut writer, BINCODE_CONFIG)?;
+    writer.into_inner().map_err(|err| err.into_error())?.finish()?;
     Ok(())
 }
 
-pub fn load_compressed_data(path: &Path) -> Result<GeoData, Box<dyn Error>> {
-    let file = File::open(path)?;
-    let decoder = GzDecoder::new(file);
-    let reader = BufReader::new(decoder);
-    let data: GeoData = bincode::decode_from_reader(reader, BINCODE_CONFIG)?;
+/// Loads a cache written by [`save_compressed_data`].
+///
+/// The cache must match the current format version and, when `expected` is
+/// given, the source it was built from; otherwise [`GeoLoadError::StaleCache`]
+/// is returned.
+pub fn load_compressed_data<T: Decode<()>>(path: &Path, expected: Option<&CacheHeader>) -> Result<T, GeoLoadError> {
+    decode(BufReader::new(File::open(path)?), expected)
+}
+
+/// Loads a cache written by [`save_compressed_data`] from memory, e.g. one
+/// embedded with `include_bytes!`.
+///
+/// Only the format version is checked, there is no source to compare against.
+pub fn load_compressed_bytes<T: Decode<()>>(bytes: &[u8]) -> Result<T, GeoLoadError> {
+    decode(bytes, None)
+}
+
+fn decode<T: Decode<()>>(mut input: impl BufRead, expected: Option<&CacheHeader>) -> Result<T, GeoLoadError> {
+    let codec = Codec::detect(input.fill_buf()?)?;
+    let mut reader = BufReader::new(codec.decoder(input)?);
+    let header: CacheHeader = bincode::decode_from_std_read(&mut reader, BINCODE_CONFIG)?;
+    if header.version != CACHE_FORMAT_VERSION || expected.is_some_and(|expected| *expected != header) {
+        return Err(GeoLoadError::StaleCache);
+    }
+    let data: T = bincode::decode_from_std_read(&mut reader, BINCODE_CONFIG)?;
+    Ok(data)
+}
+
+/// Loads the data from the cache at `cache_path`, rebuilding the cache with
+/// `parse` from the source archive when it is missing, corrupt or stale.
+///
+/// A rebuilt cache always uses the default codec. When `cache_path` has the
+/// extension of another codec, e.g. a gzip cache from before enabling `zstd`,
+/// the rebuild is written next to it with the default extension and the old
+/// cache is removed.
+///
+/// Without a readable source archive the cache is used as-is.
+pub fn load_cached<T: Encode + Decode<()>>(
+    source_path: &Path,
+    cache_path: &Path,
+    parse: impl FnOnce(&Path) -> Result<T, GeoLoadError>,
+) -> Result<T, GeoLoadError> {
+    let header = CacheHeader::for_source(source_path);
+
+    if cache_path.exists() {
+        match load_compressed_data(cache_path, header.as_ref().ok()) {
+            Ok(data) => return Ok(data),
+            Err(err) if header.is_ok() => {
+                warn!("Rebuilding cache {}: {}", cache_path.display(), err)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let header = header?;
+    let data = parse(source_path)?;
+    let rebuilt = default_codec_path(cache_path);
+    save_compressed_data(&data, &header, &rebuilt)?;
+    if rebuilt != cache_path && cache_path.exists() {
+        if let Err(err) = std::fs::remove_file(cache_path) {
+            warn!("Failed to remove old cache {}: {}", cache_path.display(), err);
+        }
+    }
     Ok(data)
-}
\ No newline at end of file
+}
+
+/// `path` with the default codec's extension if it has another codec's.
+fn default_codec_path(path: &Path) -> PathBuf {
+    let other_codec = Codec::ALL.iter().any(|codec| {
+        *codec != Codec::DEFAULT && path.extension() == Some(codec.extension().as_ref())
+    });
+    if other_codec {
+        path.with_extension(Codec::DEFAULT.extension())
+    } else {
+        path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(codec: Codec) {
+        let path = std::env::temp_dir().join(format!(
+            "tower-ipfilter-codec-{}-{}.bin",
+            codec.extension(),
+            std::process::id()
+        ));
+        let header = CacheHeader {
+            version: CACHE_FORMAT_VERSION,
+            source_len: 1,
+            source_modified: 2,
+        };
+        save_with(codec, &vec![1u32, 2, 3], &header, &path).unwrap();
+        let data: Vec<u32> = load_compressed_data(&path, Some(&header)).unwrap();
+        assert_eq!(data, vec![1, 2, 3]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        round_trip(Codec::Gzip);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_round_trip() {
+        round_trip(Codec::Zstd);
+    }
+
+    #[test]
+    fn test_cache_file_falls_back_to_existing_cache() {
+        let dir = std::env::temp_dir().join(format!(
+            "tower-ipfilter-cache-file-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let preferred = dir.join(format!("geo.bin.{}", Codec::DEFAULT.extension()));
+        assert_eq!(cache_file_in(&dir, "geo"), preferred);
+
+        let gzip = dir.join("geo.bin.gz");
+        std::fs::write(&gzip, b"").unwrap();
+        assert_eq!(cache_file_in(&dir, "geo"), gzip);
+
+        std::fs::write(&preferred, b"").unwrap();
+        assert_eq!(cache_file_in(&dir, "geo"), preferred);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_uncompressed_cache_is_rejected() {
+        let path = std::env::temp_dir().join(format!(
+            "tower-ipfilter-codec-plain-{}.bin",
+            std::process::id()
+        ));
+        std::fs::write(&path, b"plain").unwrap();
+        assert!(matches!(
+            load_compressed_data::<Vec<u32>>(&path, None),
+            Err(GeoLoadError::Io(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_rebuilt_gzip_cache_moves_to_default_codec() {
+        let dir = std::env::temp_dir().join(format!(
+            "tower-ipfilter-cache-rebuild-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source.zip");
+        std::fs::write(&source, b"source").unwrap();
+        let stale = CacheHeader {
+            version: CACHE_FORMAT_VERSION - 1,
+            source_len: 0,
+            source_modified: 0,
+        };
+        let gzip = dir.join("geo.bin.gz");
+        save_with(Codec::Gzip, &vec![1u32], &stale, &gzip).unwrap();
+        assert_eq!(cache_file_in(&dir, "geo"), gzip);
+
+        let data: Vec<u32> = load_cached(&source, &gzip, |_| Ok(vec![2])).unwrap();
+        assert_eq!(data, vec![2]);
+        let zstd = dir.join("geo.bin.zst");
+        assert!(!gzip.exists());
+        assert_eq!(cache_file_in(&dir, "geo"), zstd);
+        let mut magic = [0; 4];
+        File::open(&zstd).unwrap().read_exact(&mut magic).unwrap();
+        assert_eq!(&magic[..], ZSTD_MAGIC);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
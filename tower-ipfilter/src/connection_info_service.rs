@@ -1,18 +1,26 @@
 use std::{
     net::IpAddr,
+    sync::Arc,
     task::{Context, Poll},
 };
 use http::Request;
+use ipnetwork::IpNetwork;
 use tower::{Layer, Service};
 
+use crate::types::CountryLocation;
+
 #[derive(Clone, Debug)]
 pub struct AddConnectionInfo<S> {
     inner: S,
+    config: ExtractConfig,
 }
 
 impl<S> AddConnectionInfo<S> {
     pub fn new(inner: S) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            config: ExtractConfig::default(),
+        }
     }
 }
 
@@ -29,74 +37,483 @@ where
     }
 
     fn call(&mut self, mut req: Request<B>) -> Self::Future {
-        if let Some(ip_addr) = extract_ip(&req) {
-            req.extensions_mut().insert(ConnectionInfo { ip_addr });
+        if let Some(ip_addr) = extract_ip(&req, &self.config) {
+            req.extensions_mut().insert(ConnectionInfo {
+                ip_addr,
+                country: None,
+            });
         }
         self.inner.call(req)
     }
 }
 
+/// Where [`AddConnectionInfo`] looks first for the client address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExtractionStrategy {
+    /// Forwarding headers, then the connection peer. For deployments behind a proxy.
+    #[default]
+    HeadersFirst,
+    /// The connection peer, then forwarding headers. For deployments without a
+    /// proxy, where every header is client-controlled.
+    PeerFirst,
+}
+
+/// How the client address is picked out of a request.
+#[derive(Clone, Debug, Default)]
+struct ExtractConfig {
+    strategy: ExtractionStrategy,
+    trusted_proxies: Option<Arc<[IpNetwork]>>,
+    reject_private: Option<bool>,
+}
+
+impl ExtractConfig {
+    const DEFAULT: Self = Self {
+        strategy: ExtractionStrategy::HeadersFirst,
+        trusted_proxies: None,
+        reject_private: None,
+    };
+
+    #[cfg(any(feature = "axum", feature = "hyper"))]
+    fn rejects_private(&self) -> bool {
+        self.reject_private.unwrap_or(self.trusted_proxies.is_some())
+    }
+}
+
+fn extract_ip<B>(req: &Request<B>, config: &ExtractConfig) -> Option<IpAddr> {
+    // The PROXY protocol header comes from our own proxy, so it beats any HTTP header.
+    #[cfg(feature = "proxy-protocol")]
+    if let Some(addr) = req.extensions().get::<ProxyProtocolAddr>() {
+        return Some(addr.0.ip());
+    }
 
-fn extract_ip<B>(req: &Request<B>) -> Option<IpAddr> {
     cfg_if::cfg_if! {
-            if #[cfg(feature = "axum")] {
-                use axum_impl::extract_ip_axum;
-                return extract_ip_axum(&req)
-            } else if #[cfg(feature = "hyper")] {
-                use hyper_impl::extract_ip_hyper;
-                return extract_ip_hyper(&req)
-            } else {
-                panic!("Either axum or hyper feature must be enabled")
+        if #[cfg(feature = "axum")] {
+            resolve_ip(req, config, axum_impl::peer_ip(req))
+        } else if #[cfg(feature = "hyper")] {
+            resolve_ip(req, config, hyper_impl::peer_ip(req))
+        } else {
+            let _ = (req, config);
+            panic!("Either axum or hyper feature must be enabled")
+        }
+    }
+}
+
+#[cfg(any(feature = "axum", feature = "hyper"))]
+const FORWARDING_HEADERS: [&str; 4] = [
+    "CF-Connecting-IP",
+    "True-Client-IP",
+    "X-Real-IP",
+    "X-Forwarded-For",
+];
+
+/// Picks the client address from the forwarding headers, falling back to `peer`.
+#[cfg(any(feature = "axum", feature = "hyper"))]
+fn resolve_ip<B>(
+    req: &Request<B>,
+    config: &ExtractConfig,
+    peer: Option<IpAddr>,
+) -> Option<IpAddr> {
+    if config.strategy == ExtractionStrategy::PeerFirst && peer.is_some() {
+        return peer;
+    }
+    if let Some(trusted) = &config.trusted_proxies {
+        // Headers on a connection that didn't come through a proxy are client-controlled.
+        if !peer.is_some_and(|peer| is_trusted(trusted, peer)) {
+            return peer;
+        }
+    }
+
+    let reject_private = config.rejects_private();
+    let usable = |ip: &IpAddr| !(reject_private && is_internal(ip));
+    FORWARDING_HEADERS
+        .iter()
+        .find_map(|header| {
+            // A header may be sent as several lines as well as one comma-joined line.
+            let mut chain = req
+                .headers()
+                .get_all(*header)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .filter_map(parse_forwarded_ip);
+            match &config.trusted_proxies {
+                // Each proxy appends the address it saw, so everything left of the
+                // first untrusted hop from the right was written by the client.
+                Some(trusted) => chain
+                    .rev()
+                    .find(|ip| !is_trusted(trusted, *ip))
+                    .filter(usable),
+                None => chain.find(usable),
             }
-        };
+        })
+        .or(peer)
+}
+
+#[cfg(any(feature = "axum", feature = "hyper"))]
+fn is_trusted(trusted: &[IpNetwork], ip: IpAddr) -> bool {
+    trusted.iter().any(|network| network.contains(ip))
+}
+
+/// Whether `ip` is loopback, private, link-local or unspecified, i.e. an
+/// address no client on the internet can legitimately claim.
+#[cfg(any(feature = "axum", feature = "hyper"))]
+fn is_internal(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(&IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // fc00::/7 unique local and fe80::/10 link-local.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct AddConnectionInfoLayer;
+/// Parses one address from a forwarding header.
+///
+/// Proxies may append a port, so besides bare addresses this accepts
+/// `203.0.113.5:443`, `[2001:db8::1]:8080` and `[2001:db8::1]`.
+#[cfg(any(feature = "axum", feature = "hyper"))]
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    if let Ok(ip) = value.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = value.parse::<std::net::SocketAddr>() {
+        return Some(addr.ip());
+    }
+    value
+        .strip_prefix('[')
+        .and_then(|value| value.strip_suffix(']'))
+        .and_then(|value| value.parse().ok())
+}
+
+/// Adds a [`ConnectionInfo`] with the client address to each request.
+///
+/// The address is taken from the first usable forwarding header
+/// (`CF-Connecting-IP`, `True-Client-IP`, `X-Real-IP`, `X-Forwarded-For`),
+/// falling back to the connection peer.
+#[derive(Clone, Debug, Default)]
+pub struct AddConnectionInfoLayer {
+    config: ExtractConfig,
+}
+
+impl AddConnectionInfoLayer {
+    /// The layer with the default settings, usable in `const` and `static` items.
+    pub const fn new() -> Self {
+        Self {
+            config: ExtractConfig::DEFAULT,
+        }
+    }
+
+    /// Sets whether forwarding headers or the connection peer are consulted
+    /// first, defaults to [`ExtractionStrategy::HeadersFirst`].
+    pub fn extraction_strategy(mut self, strategy: ExtractionStrategy) -> Self {
+        self.config.strategy = strategy;
+        self
+    }
+
+    /// Only honours forwarding headers on connections from these networks.
+    ///
+    /// Requests arriving directly from anywhere else are identified by their
+    /// peer address. Through a trusted proxy, each header is read from the
+    /// right, skipping the hops inside these networks: the first other address
+    /// is the one the outermost trusted proxy saw, anything left of it was sent
+    /// by the client and is ignored. Configuring trusted proxies also turns on
+    /// [`reject_private_forwarded`](Self::reject_private_forwarded) unless it
+    /// was set explicitly.
+    pub fn trusted_proxies(mut self, proxies: impl IntoIterator<Item = IpNetwork>) -> Self {
+        self.config.trusted_proxies = Some(proxies.into_iter().collect());
+        self
+    }
+
+    /// Security hardening: skips forwarded addresses in loopback, private or
+    /// link-local ranges.
+    ///
+    /// Without this a client can send `X-Forwarded-For: 127.0.0.1` and be
+    /// treated as a local connection. Rejected addresses fall through to the
+    /// next entry in the header, the next header and finally the connection
+    /// peer; with [`trusted_proxies`](Self::trusted_proxies) they fall through
+    /// to the next header, as the entries left of them are client-controlled.
+    ///
+    /// Defaults to on when [`trusted_proxies`](Self::trusted_proxies) are
+    /// configured and off otherwise.
+    pub fn reject_private_forwarded(mut self, reject: bool) -> Self {
+        self.config.reject_private = Some(reject);
+        self
+    }
+}
 
 impl<S: Clone> Layer<S> for AddConnectionInfoLayer {
     type Service = AddConnectionInfo<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        AddConnectionInfo::new(service)
+        AddConnectionInfo {
+            inner: service,
+            config: self.config.clone(),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct ConnectionInfo {
     pub ip_addr: IpAddr,
+    /// The country resolved by a geo filter that allowed the request.
+    pub country: Option<Arc<CountryLocation>>,
 }
 
+#[cfg(feature = "proxy-protocol")]
+mod proxy_protocol {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    const V1_PREFIX: &[u8] = b"PROXY ";
+    const V1_MAX_LEN: usize = 107;
+    const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+    const V2_HEADER_LEN: usize = 16;
+
+    /// Client address read from a PROXY protocol header.
+    ///
+    /// Whatever accepts connections should parse the header with
+    /// [`parse_proxy_header`] and insert this into each request's extensions;
+    /// [`AddConnectionInfo`](super::AddConnectionInfo) then prefers it over
+    /// forwarded HTTP headers.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ProxyProtocolAddr(pub SocketAddr);
+
+    /// A parsed PROXY protocol (v1 or v2) header.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ProxyHeader {
+        /// The original client address, `None` for `UNKNOWN`/`LOCAL` connections
+        /// or unsupported address families.
+        pub source: Option<SocketAddr>,
+        pub destination: Option<SocketAddr>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ProxyHeaderError {
+        /// More bytes are needed to parse the header.
+        Incomplete,
+        /// The data starts like a PROXY header but is malformed.
+        Invalid,
+    }
+
+    impl std::fmt::Display for ProxyHeaderError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                ProxyHeaderError::Incomplete => write!(f, "incomplete PROXY protocol header"),
+                ProxyHeaderError::Invalid => write!(f, "invalid PROXY protocol header"),
+            }
+        }
+    }
+
+    impl std::error::Error for ProxyHeaderError {}
+
+    /// Parses a PROXY protocol header from the start of a connection.
+    ///
+    /// Returns `Ok(None)` if `buf` does not start with a PROXY header, otherwise
+    /// the header and the number of bytes it occupies.
+    pub fn parse_proxy_header(
+        buf: &[u8],
+    ) -> Result<Option<(ProxyHeader, usize)>, ProxyHeaderError> {
+        if starts_with_prefix(buf, V2_SIGNATURE) {
+            parse_v2(buf).map(Some)
+        } else if starts_with_prefix(buf, V1_PREFIX) {
+            parse_v1(buf).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether `buf` starts with `prefix`, or with part of it when `buf` is shorter.
+    fn starts_with_prefix(buf: &[u8], prefix: &[u8]) -> bool {
+        let len = buf.len().min(prefix.len());
+        !buf.is_empty() && buf[..len] == prefix[..len]
+    }
+
+    fn parse_v1(buf: &[u8]) -> Result<(ProxyHeader, usize), ProxyHeaderError> {
+        let search = &buf[..buf.len().min(V1_MAX_LEN)];
+        let Some(end) = search.windows(2).position(|w| w == b"\r\n") else {
+            return if buf.len() >= V1_MAX_LEN {
+                Err(ProxyHeaderError::Invalid)
+            } else {
+                Err(ProxyHeaderError::Incomplete)
+            };
+        };
+        let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])
+            .map_err(|_| ProxyHeaderError::Invalid)?;
+        let parts: Vec<&str> = line.split(' ').collect();
+
+        let header = match parts.as_slice() {
+            ["UNKNOWN", ..] => ProxyHeader {
+                source: None,
+                destination: None,
+            },
+            [family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+                let parse = |ip: &str, port: &str| -> Result<SocketAddr, ProxyHeaderError> {
+                    let ip = match *family {
+                        "TCP4" => ip.parse::<Ipv4Addr>().map(Into::into),
+                        _ => ip.parse::<Ipv6Addr>().map(Into::into),
+                    }
+                    .map_err(|_| ProxyHeaderError::Invalid)?;
+                    let port = port.parse().map_err(|_| ProxyHeaderError::Invalid)?;
+                    Ok(SocketAddr::new(ip, port))
+                };
+                ProxyHeader {
+                    source: Some(parse(src, src_port)?),
+                    destination: Some(parse(dst, dst_port)?),
+                }
+            }
+            _ => return Err(ProxyHeaderError::Invalid),
+        };
+        Ok((header, end + 2))
+    }
+
+    fn parse_v2(buf: &[u8]) -> Result<(ProxyHeader, usize), ProxyHeaderError> {
+        if buf.len() < V2_HEADER_LEN {
+            return Err(ProxyHeaderError::Incomplete);
+        }
+        let version_command = buf[12];
+        let family = buf[13];
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        if version_command >> 4 != 2 {
+            return Err(ProxyHeaderError::Invalid);
+        }
+        let total = V2_HEADER_LEN + len;
+        if buf.len() < total {
+            return Err(ProxyHeaderError::Incomplete);
+        }
+        let addresses = &buf[V2_HEADER_LEN..total];
+
+        let local = ProxyHeader {
+            source: None,
+            destination: None,
+        };
+        let header = match (version_command & 0x0f, family >> 4) {
+            // LOCAL: health checks from the proxy itself.
+            (0x0, _) => local,
+            (0x1, 0x1) => {
+                let addresses: &[u8; 12] = addresses
+                    .get(..12)
+                    .and_then(|a| a.try_into().ok())
+                    .ok_or(ProxyHeaderError::Invalid)?;
+                let ip = |at: usize| {
+                    Ipv4Addr::new(
+                        addresses[at],
+                        addresses[at + 1],
+                        addresses[at + 2],
+                        addresses[at + 3],
+                    )
+                };
+                let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+                ProxyHeader {
+                    source: Some(SocketAddr::new(ip(0).into(), port(8))),
+                    destination: Some(SocketAddr::new(ip(4).into(), port(10))),
+                }
+            }
+            (0x1, 0x2) => {
+                let addresses: &[u8; 36] = addresses
+                    .get(..36)
+                    .and_then(|a| a.try_into().ok())
+                    .ok_or(ProxyHeaderError::Invalid)?;
+                let ip = |at: usize| {
+                    let octets: [u8; 16] = addresses[at..at + 16].try_into().unwrap();
+                    Ipv6Addr::from(octets)
+                };
+                let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+                ProxyHeader {
+                    source: Some(SocketAddr::new(ip(0).into(), port(32))),
+                    destination: Some(SocketAddr::new(ip(16).into(), port(34))),
+                }
+            }
+            // UNSPEC and unix sockets carry no IP address.
+            (0x1, _) => local,
+            _ => return Err(ProxyHeaderError::Invalid),
+        };
+        Ok((header, total))
+    }
+}
+
+#[cfg(feature = "proxy-protocol")]
+pub use proxy_protocol::{parse_proxy_header, ProxyHeader, ProxyHeaderError, ProxyProtocolAddr};
+
 #[cfg(feature = "axum")]
 mod axum_impl {
     use super::*;
-    use axum::extract::connect_info::ConnectInfo;
+    use axum::{
+        async_trait,
+        extract::{connect_info::ConnectInfo, FromRequestParts},
+        response::{IntoResponse, Response},
+    };
+    use http::{request::Parts, StatusCode};
     use std::net::SocketAddr;
 
     pub fn extract_ip_axum<B>(req: &Request<B>) -> Option<IpAddr> {
-        let headers_to_check = [
-            "CF-Connecting-IP",
-            "True-Client-IP",
-            "X-Real-IP",
-            "X-Forwarded-For",
-        ];
-
-        for header in headers_to_check.iter() {
-            if let Some(ip) = req
-                .headers()
-                .get(*header)
-                .and_then(|hv| hv.to_str().ok())
-                .and_then(|s| s.split(',').next())
-                .and_then(|s| s.trim().parse().ok())
-            {
-                return Some(ip);
-            }
-        }
+        resolve_ip(req, &ExtractConfig::default(), peer_ip(req))
+    }
 
+    pub(super) fn peer_ip<B>(req: &Request<B>) -> Option<IpAddr> {
         req.extensions()
             .get::<ConnectInfo<SocketAddr>>()
             .map(|socket_addr| socket_addr.ip())
+    }
+
+    /// Extracts the client's country, as resolved by a geo filter.
+    ///
+    /// Requires [`AddConnectionInfoLayer`] and a geo filter in front of the handler:
+    /// `async fn handler(Country(country): Country)`.
+    #[derive(Clone, Debug)]
+    pub struct Country(pub CountryLocation);
+
+    #[derive(Debug)]
+    pub enum CountryRejection {
+        /// No [`ConnectionInfo`] extension, the connection info layer is missing.
+        MissingConnectionInfo,
+        /// The client's address did not resolve to a country.
+        UnknownCountry,
+    }
+
+    impl IntoResponse for CountryRejection {
+        fn into_response(self) -> Response {
+            match self {
+                CountryRejection::MissingConnectionInfo => (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Missing connection info, is AddConnectionInfoLayer installed?",
+                )
+                    .into_response(),
+                CountryRejection::UnknownCountry => (
+                    StatusCode::BAD_REQUEST,
+                    "Could not determine country of origin",
+                )
+                    .into_response(),
+            }
+        }
+    }
 
+    #[async_trait]
+    impl<S: Send + Sync> FromRequestParts<S> for Country {
+        type Rejection = CountryRejection;
+
+        async fn from_request_parts(
+            parts: &mut Parts,
+            _state: &S,
+        ) -> Result<Self, Self::Rejection> {
+            let info = parts
+                .extensions
+                .get::<ConnectionInfo>()
+                .ok_or(CountryRejection::MissingConnectionInfo)?;
+            info.country
+                .as_deref()
+                .cloned()
+                .map(Country)
+                .ok_or(CountryRejection::UnknownCountry)
+        }
     }
 }
 
@@ -105,31 +522,291 @@ mod hyper_impl {
     use super::*;
 
     pub fn extract_ip_hyper<B>(req: &Request<B>) -> Option<IpAddr> {
-        let headers_to_check = [
-            "CF-Connecting-IP",
-            "True-Client-IP",
-            "X-Real-IP",
-            "X-Forwarded-For",
-        ];
-
-        for header in headers_to_check.iter() {
-            if let Some(ip) = req
-                .headers()
-                .get(*header)
-                .and_then(|hv| hv.to_str().ok())
-                .and_then(|s| s.split(',').next())
-                .and_then(|s| s.trim().parse().ok())
-            {
-                return Some(ip);
-            }
-        }
+        resolve_ip(req, &ExtractConfig::default(), peer_ip(req))
+    }
 
+    pub(super) fn peer_ip<B>(req: &Request<B>) -> Option<IpAddr> {
         req.uri().host().and_then(|host| host.parse().ok())
     }
 }
 
 #[cfg(feature = "axum")]
-pub use axum_impl::extract_ip_axum;
+pub use axum_impl::{extract_ip_axum, Country, CountryRejection};
 
 #[cfg(feature = "hyper")]
-pub use hyper_impl::extract_ip_hyper;
\ No newline at end of file
+pub use hyper_impl::extract_ip_hyper;
+
+#[cfg(all(test, feature = "axum"))]
+mod tests {
+    use super::*;
+    use crate::{geo_filter::GeoIpv4Filter, network_filter_service::filter};
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use dashmap::DashMap;
+    use http_body_util::BodyExt;
+    use ipnetwork::Ipv4Network;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_parse_forwarded_ip() {
+        let v4: IpAddr = "203.0.113.5".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(parse_forwarded_ip("203.0.113.5"), Some(v4));
+        assert_eq!(parse_forwarded_ip(" 203.0.113.5:443 "), Some(v4));
+        assert_eq!(parse_forwarded_ip("2001:db8::1"), Some(v6));
+        assert_eq!(parse_forwarded_ip("[2001:db8::1]"), Some(v6));
+        assert_eq!(parse_forwarded_ip("[2001:db8::1]:8080"), Some(v6));
+        assert_eq!(parse_forwarded_ip("unknown"), None);
+        assert_eq!(parse_forwarded_ip("203.0.113.5:port"), None);
+        assert_eq!(parse_forwarded_ip("[2001:db8::1"), None);
+    }
+
+    #[test]
+    fn test_extract_ip_with_ports() {
+        let req = Request::builder()
+            .header("X-Forwarded-For", "[2001:db8::1]:8080, 198.51.100.1")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            extract_ip(&req, &ExtractConfig::default()),
+            Some("2001:db8::1".parse().unwrap())
+        );
+
+        let req = Request::builder()
+            .header("X-Real-IP", "203.0.113.5:443")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            extract_ip(&req, &ExtractConfig::default()),
+            Some("203.0.113.5".parse().unwrap())
+        );
+    }
+
+    fn request_from(peer: &str, forwarded: &str) -> Request<()> {
+        let peer: std::net::SocketAddr = peer.parse().unwrap();
+        Request::builder()
+            .header("X-Forwarded-For", forwarded)
+            .extension(axum::extract::ConnectInfo(peer))
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reject_private_forwarded() {
+        let config = AddConnectionInfoLayer::new().reject_private_forwarded(true).config;
+        let ip = |forwarded| extract_ip(&request_from("198.51.100.1:443", forwarded), &config);
+
+        assert_eq!(ip("127.0.0.1, 203.0.113.5"), Some("203.0.113.5".parse().unwrap()));
+        assert_eq!(ip("10.0.0.1"), Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(ip("[fe80::1], ::ffff:192.168.0.1"), Some("198.51.100.1".parse().unwrap()));
+
+        let default = ExtractConfig::default();
+        let req = request_from("198.51.100.1:443", "127.0.0.1");
+        assert_eq!(extract_ip(&req, &default), Some("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_peer_first() {
+        let config = AddConnectionInfoLayer::new()
+            .extraction_strategy(ExtractionStrategy::PeerFirst)
+            .config;
+
+        let req = request_from("198.51.100.1:443", "203.0.113.5");
+        assert_eq!(extract_ip(&req, &config), Some("198.51.100.1".parse().unwrap()));
+
+        // Without connect info the headers are still used.
+        let req = Request::builder()
+            .header("X-Forwarded-For", "203.0.113.5")
+            .body(())
+            .unwrap();
+        assert_eq!(extract_ip(&req, &config), Some("203.0.113.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let config = AddConnectionInfoLayer::new()
+            .trusted_proxies(["10.0.0.0/8".parse().unwrap()])
+            .config;
+
+        // Through the proxy, private forwarded addresses are rejected by default.
+        let req = request_from("10.0.0.2:443", "127.0.0.1, 203.0.113.5");
+        assert_eq!(extract_ip(&req, &config), Some("203.0.113.5".parse().unwrap()));
+
+        // The client's own entry is ignored, whatever it claims.
+        let req = request_from("10.0.0.2:443", "1.2.3.4, 203.0.113.5");
+        assert_eq!(extract_ip(&req, &config), Some("203.0.113.5".parse().unwrap()));
+        let req = request_from("10.0.0.2:443", "1.2.3.4, 203.0.113.5, 10.0.0.7");
+        assert_eq!(extract_ip(&req, &config), Some("203.0.113.5".parse().unwrap()));
+        // A private address seen by the proxy isn't skipped in favour of the client's entry.
+        let req = request_from("10.0.0.2:443", "1.2.3.4, 192.168.1.9");
+        assert_eq!(extract_ip(&req, &config), Some("10.0.0.2".parse().unwrap()));
+
+        // Straight from the client, headers are ignored.
+        let req = request_from("198.51.100.1:443", "203.0.113.5");
+        assert_eq!(extract_ip(&req, &config), Some("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_forwarded_header_lines() {
+        let config = AddConnectionInfoLayer::new()
+            .trusted_proxies(["10.0.0.0/8".parse().unwrap()])
+            .config;
+        let mut req = request_from("10.0.0.2:443", "127.0.0.1");
+        req.headers_mut()
+            .append("X-Forwarded-For", "10.0.0.5, 203.0.113.5".parse().unwrap());
+
+        assert_eq!(extract_ip(&req, &config), Some("203.0.113.5".parse().unwrap()));
+
+        // The spoofed entry and the hop the proxy added arrive as separate lines.
+        let mut req = request_from("10.0.0.2:443", "1.2.3.4");
+        req.headers_mut()
+            .append("X-Forwarded-For", "203.0.113.5".parse().unwrap());
+        req.headers_mut()
+            .append("X-Forwarded-For", "10.0.0.5".parse().unwrap());
+        assert_eq!(extract_ip(&req, &config), Some("203.0.113.5".parse().unwrap()));
+    }
+
+    async fn country_handler(Country(country): Country) -> String {
+        country.country_name.unwrap_or_default()
+    }
+
+    fn create_geo_filter() -> GeoIpv4Filter {
+        let networks = DashMap::new();
+        networks.insert(
+            "203.0.113.0/24".parse::<Ipv4Network>().unwrap(),
+            CountryLocation {
+                geoname_id: 3017382,
+                locale_code: "en".to_string(),
+                continent_code: "EU".to_string(),
+                continent_name: "Europe".to_string(),
+                country_iso_code: Some("FR".to_string()),
+                country_name: Some("France".to_string()),
+                is_in_european_union: true,
+            },
+        );
+        GeoIpv4Filter::from_networks(Default::default(), networks)
+    }
+
+    async fn send(app: Router, ip: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .uri("/")
+            .header("X-Forwarded-For", ip)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_country_extractor() {
+        let app = Router::new()
+            .route("/", get(country_handler))
+            .layer(filter(create_geo_filter()))
+            .layer(AddConnectionInfoLayer::new());
+
+        assert_eq!(
+            send(app.clone(), "203.0.113.7").await,
+            (StatusCode::OK, "France".to_string())
+        );
+        assert_eq!(send(app, "198.51.100.7").await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_country_extractor_without_layer() {
+        let app = Router::new().route("/", get(country_handler));
+
+        assert_eq!(
+            send(app, "203.0.113.7").await.0,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
+
+#[cfg(all(test, feature = "proxy-protocol"))]
+mod proxy_protocol_tests {
+    use super::*;
+    use std::net::{Ipv6Addr, SocketAddr};
+
+    #[test]
+    fn test_parse_v1() {
+        let buf = b"PROXY TCP4 203.0.113.5 192.0.2.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (header, len) = parse_proxy_header(buf).unwrap().unwrap();
+        assert_eq!(header.source, Some("203.0.113.5:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("192.0.2.1:443".parse().unwrap()));
+        assert_eq!(&buf[len..], b"GET / HTTP/1.1\r\n");
+
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let (header, _) = parse_proxy_header(buf).unwrap().unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (header, _) = parse_proxy_header(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(header.source, None);
+    }
+
+    #[test]
+    fn test_parse_v1_errors() {
+        assert_eq!(
+            parse_proxy_header(b"PROXY TCP4 203.0.113.5"),
+            Err(ProxyHeaderError::Incomplete)
+        );
+        assert_eq!(
+            parse_proxy_header(b"PRO"),
+            Err(ProxyHeaderError::Incomplete)
+        );
+        assert_eq!(
+            parse_proxy_header(b"PROXY TCP4 not-an-ip 192.0.2.1 1 2\r\n"),
+            Err(ProxyHeaderError::Invalid)
+        );
+        assert_eq!(parse_proxy_header(b"GET / HTTP/1.1\r\n"), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        buf.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        buf.extend_from_slice(&[203, 0, 113, 5, 192, 0, 2, 1]);
+        buf.extend_from_slice(&56324u16.to_be_bytes());
+        buf.extend_from_slice(&443u16.to_be_bytes());
+        buf.extend_from_slice(b"GET");
+
+        let (header, len) = parse_proxy_header(&buf).unwrap().unwrap();
+        assert_eq!(header.source, Some("203.0.113.5:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("192.0.2.1:443".parse().unwrap()));
+        assert_eq!(&buf[len..], b"GET");
+
+        assert_eq!(
+            parse_proxy_header(&buf[..20]),
+            Err(ProxyHeaderError::Incomplete)
+        );
+
+        let mut buf = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        buf.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        buf.extend_from_slice(&source.octets());
+        buf.extend_from_slice(&destination.octets());
+        buf.extend_from_slice(&8080u16.to_be_bytes());
+        buf.extend_from_slice(&443u16.to_be_bytes());
+        let (header, _) = parse_proxy_header(&buf).unwrap().unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:8080".parse().unwrap()));
+
+        let mut local = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        let (header, len) = parse_proxy_header(&local).unwrap().unwrap();
+        assert_eq!(header.source, None);
+        assert_eq!(len, 16);
+    }
+
+    #[test]
+    fn test_proxy_addr_takes_precedence() {
+        let addr: SocketAddr = "203.0.113.5:56324".parse().unwrap();
+        let req = Request::builder()
+            .header("X-Forwarded-For", "198.51.100.1")
+            .extension(ProxyProtocolAddr(addr))
+            .body(())
+            .unwrap();
+        assert_eq!(extract_ip(&req, &ExtractConfig::default()), Some(addr.ip()));
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+
+/// Errors raised while loading GeoIP data from an archive or the compressed cache.
+#[derive(Debug)]
+pub enum GeoLoadError {
+    /// Reading or writing a file failed.
+    Io(std::io::Error),
+    /// The archive is not a readable zip file.
+    Zip(zip::result::ZipError),
+    /// A CSV file in the archive could not be parsed.
+    Csv(csv::Error),
+    /// The archive does not contain the named file.
+    MissingEntry(String),
+    /// The compressed cache could not be decoded, e.g. it is truncated.
+    Cache(bincode::error::DecodeError),
+    /// The compressed cache could not be written.
+    CacheEncode(bincode::error::EncodeError),
+    /// The compressed cache was built by another format version or from a different source.
+    StaleCache,
+    /// The locale is not a locale code such as `en` or `pt-BR`.
+    InvalidLocale(String),
+    /// A MaxMind database could not be read.
+    #[cfg(feature = "mmdb")]
+    Mmdb(maxminddb::MaxMindDBError),
+    /// Watching a file for changes failed.
+    #[cfg(feature = "notify")]
+    Watch(notify::Error),
+}
+
+impl fmt::Display for GeoLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoLoadError::Io(err) => write!(f, "io error: {}", err),
+            GeoLoadError::Zip(err) => write!(f, "zip error: {}", err),
+            GeoLoadError::Csv(err) => write!(f, "csv error: {}", err),
+            GeoLoadError::MissingEntry(name) => write!(f, "archive has no entry {}", name),
+            GeoLoadError::Cache(err) => write!(f, "failed to decode cache: {}", err),
+            GeoLoadError::CacheEncode(err) => write!(f, "failed to encode cache: {}", err),
+            GeoLoadError::StaleCache => write!(f, "cache does not match its source"),
+            GeoLoadError::InvalidLocale(locale) => {
+                write!(
+                    f,
+                    "invalid locale {:?}, expected a code such as en or pt-BR",
+                    locale
+                )
+            }
+            #[cfg(feature = "mmdb")]
+            GeoLoadError::Mmdb(err) => write!(f, "mmdb error: {}", err),
+            #[cfg(feature = "notify")]
+            GeoLoadError::Watch(err) => write!(f, "watch error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for GeoLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GeoLoadError::Io(err) => Some(err),
+            GeoLoadError::Zip(err) => Some(err),
+            GeoLoadError::Csv(err) => Some(err),
+            GeoLoadError::MissingEntry(_) => None,
+            GeoLoadError::Cache(err) => Some(err),
+            GeoLoadError::CacheEncode(err) => Some(err),
+            GeoLoadError::StaleCache => None,
+            GeoLoadError::InvalidLocale(_) => None,
+            #[cfg(feature = "mmdb")]
+            GeoLoadError::Mmdb(err) => Some(err),
+            #[cfg(feature = "notify")]
+            GeoLoadError::Watch(err) => Some(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for GeoLoadError {
+    fn from(err: std::io::Error) -> Self {
+        GeoLoadError::Io(err)
+    }
+}
+
+impl From<zip::result::ZipError> for GeoLoadError {
+    fn from(err: zip::result::ZipError) -> Self {
+        GeoLoadError::Zip(err)
+    }
+}
+
+impl From<csv::Error> for GeoLoadError {
+    fn from(err: csv::Error) -> Self {
+        GeoLoadError::Csv(err)
+    }
+}
+
+impl From<bincode::error::DecodeError> for GeoLoadError {
+    fn from(err: bincode::error::DecodeError) -> Self {
+        GeoLoadError::Cache(err)
+    }
+}
+
+impl From<bincode::error::EncodeError> for GeoLoadError {
+    fn from(err: bincode::error::EncodeError) -> Self {
+        GeoLoadError::CacheEncode(err)
+    }
+}
+
+#[cfg(feature = "mmdb")]
+impl From<maxminddb::MaxMindDBError> for GeoLoadError {
+    fn from(err: maxminddb::MaxMindDBError) -> Self {
+        GeoLoadError::Mmdb(err)
+    }
+}
+
+#[cfg(feature = "notify")]
+impl From<notify::Error> for GeoLoadError {
+    fn from(err: notify::Error) -> Self {
+        GeoLoadError::Watch(err)
+    }
+}
+
+/// Returned when parsing a [`BlockDate`](crate::ip_filter::BlockDate) from a
+/// string that is not a valid `YYYY-MM-DD` date or `YYYY-MM-DDTHH:MM:SSZ` timestamp.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DateParseError(pub(crate) String);
+
+impl fmt::Display for DateParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid date {:?}, expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for DateParseError {}
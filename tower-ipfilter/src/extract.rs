@@ -1,35 +1,348 @@
-use crate::types::{CountryLocation, GeoData, IpBlock};
+use crate::error::GeoLoadError;
+use crate::types::{AsnBlock, AsnData, CountryLocation, CountryNetworks, IpBlock};
+use dashmap::DashMap;
 use std::collections::HashMap;
-use std::io::BufReader;
-use std::{error::Error, fs::File, path::Path};
+use std::hash::Hash;
+use std::io::{BufReader, Read, Seek};
+use std::str::FromStr;
+use std::{fs::File, path::Path};
+use zip::read::ZipFile;
+use zip::result::ZipError;
+use zip::ZipArchive;
 
-pub fn extract_and_parse_csv(path_to_data: &Path ) -> Result<GeoData, Box<dyn Error>> {
- 
+/// The locale of the country names read by default, see [`extract_networks`].
+pub(crate) const DEFAULT_LOCALE: &str = "en";
+
+const LOCATIONS_PREFIX: &str = "GeoLite2-Country-Locations-";
+
+/// Parses a GeoLite2-Country CSV archive into networks resolved to their country.
+///
+/// Country names are read from the `locale` locations file, e.g.
+/// `GeoLite2-Country-Locations-de.csv`. An archive with a single locations
+/// file in another locale is used as-is, see [`locations_entry`].
+///
+/// The locations are read first so the much larger blocks files can be streamed
+/// straight into the resulting maps. The IPv6 blocks are optional, archives
+/// without them yield no IPv6 networks.
+pub(crate) fn extract_networks(
+    path_to_data: &Path,
+    locale: &str,
+) -> Result<CountryNetworks, GeoLoadError> {
     let file = File::open(path_to_data)?;
     let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
 
-    let mut ip_blocks = Vec::new();
+    let mut country_locations = HashMap::new();
     {
-        let ipv4_file =
-            archive.by_name("GeoLite2-Country-CSV_20241015/GeoLite2-Country-Blocks-IPv4.csv")?;
-        let mut rdr = csv::Reader::from_reader(ipv4_file);
+        let (name, _) = locations_entry(&archive, locale)?;
+        let locations_file = by_name(&mut archive, &name)?;
+        let mut rdr = csv::Reader::from_reader(locations_file);
         for result in rdr.deserialize() {
-            let record: IpBlock = result?;
-            ip_blocks.push(record);
+            let record: CountryLocation = result?;
+            country_locations.insert(record.geoname_id, record);
         }
     }
 
-    let mut country_locations = HashMap::new();
-    let locations_file =
-        archive.by_name("GeoLite2-Country-CSV_20241015/GeoLite2-Country-Locations-en.csv")?;
-    let mut rdr = csv::Reader::from_reader(locations_file);
+    let networks = CountryNetworks::default();
+    let ipv4_file = by_name(
+        &mut archive,
+        "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Blocks-IPv4.csv",
+    )?;
+    read_blocks(ipv4_file, &country_locations, &networks.v4)?;
+
+    match by_name(
+        &mut archive,
+        "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Blocks-IPv6.csv",
+    ) {
+        Ok(ipv6_file) => read_blocks(ipv6_file, &country_locations, &networks.v6)?,
+        Err(GeoLoadError::MissingEntry(_)) => {}
+        Err(err) => return Err(err),
+    }
+
+    Ok(networks)
+}
+
+/// The locale [`extract_networks`] reads the country names in when asked for
+/// `locale`, which differs when the archive falls back to another one.
+pub(crate) fn archive_locale(path_to_data: &Path, locale: &str) -> Result<String, GeoLoadError> {
+    let archive = ZipArchive::new(BufReader::new(File::open(path_to_data)?))?;
+    let (_, found) = locations_entry(&archive, locale)?;
+    Ok(found)
+}
+
+/// Finds the locations file for `locale`, falling back to the only locations
+/// file in the archive if there is exactly one. Returns the entry name and the
+/// locale of the file found.
+///
+/// Otherwise fails with [`GeoLoadError::MissingEntry`] naming the locales the
+/// archive does have.
+fn locations_entry<R: Read + Seek>(
+    archive: &ZipArchive<R>,
+    locale: &str,
+) -> Result<(String, String), GeoLoadError> {
+    let mut available: Vec<(&str, &str)> = archive
+        .file_names()
+        .filter_map(|name| {
+            let file_name = name.rsplit('/').next()?;
+            let found = file_name
+                .strip_prefix(LOCATIONS_PREFIX)?
+                .strip_suffix(".csv")?;
+            Some((name, found))
+        })
+        .collect();
+    available.sort_unstable_by_key(|(_, found)| *found);
+
+    if let Some((name, found)) = available.iter().find(|(_, found)| *found == locale) {
+        return Ok((name.to_string(), found.to_string()));
+    }
+    match available.as_slice() {
+        [(name, found)] => {
+            tracing::warn!(
+                "No {} locations in archive, using {} instead",
+                locale,
+                found
+            );
+            Ok((name.to_string(), found.to_string()))
+        }
+        _ => {
+            let locales: Vec<&str> = available.iter().map(|(_, found)| *found).collect();
+            Err(GeoLoadError::MissingEntry(format!(
+                "{}{}.csv (available locales: {})",
+                LOCATIONS_PREFIX,
+                locale,
+                if locales.is_empty() {
+                    "none".to_string()
+                } else {
+                    locales.join(", ")
+                },
+            )))
+        }
+    }
+}
+
+fn read_blocks<N: FromStr + Eq + Hash>(
+    file: impl Read,
+    country_locations: &HashMap<u32, CountryLocation>,
+    networks: &DashMap<N, CountryLocation>,
+) -> Result<(), GeoLoadError> {
+    let mut rdr = csv::Reader::from_reader(file);
+    for result in rdr.deserialize() {
+        let block: IpBlock = result?;
+        if let Some((network, country)) = resolve_block(&block, country_locations) {
+            networks.insert(network, country);
+        }
+    }
+    Ok(())
+}
+
+/// Resolves `block` to its network and country location, skipping blocks
+/// without a country or with an unparsable network.
+pub(crate) fn resolve_block<N: FromStr>(
+    block: &IpBlock,
+    country_locations: &HashMap<u32, CountryLocation>,
+) -> Option<(N, CountryLocation)> {
+    let geoname_id = block.geoname_id?;
+    let network = block.network.parse().ok()?;
+    match country_locations.get(&geoname_id) {
+        Some(country) => Some((network, country.clone())),
+        None => {
+            tracing::debug!("No country found for geoname_id: {}", geoname_id);
+            None
+        }
+    }
+}
+
+/// Parses the IPv4 blocks of a GeoLite2-ASN CSV archive.
+pub fn extract_and_parse_asn_csv(path_to_data: &Path) -> Result<AsnData, GeoLoadError> {
+    let file = File::open(path_to_data)?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+
+    // The directory inside the archive carries the release date, so match on the file name.
+    let name = archive
+        .file_names()
+        .find(|name| name.ends_with("GeoLite2-ASN-Blocks-IPv4.csv"))
+        .map(str::to_string)
+        .ok_or_else(|| GeoLoadError::MissingEntry("GeoLite2-ASN-Blocks-IPv4.csv".to_string()))?;
+
+    let mut blocks = Vec::new();
+    let ipv4_file = by_name(&mut archive, &name)?;
+    let mut rdr = csv::Reader::from_reader(ipv4_file);
     for result in rdr.deserialize() {
-        let record: CountryLocation = result?;
-        country_locations.insert(record.geoname_id, record);
+        let record: AsnBlock = result?;
+        blocks.push(record);
     }
 
-    Ok(GeoData {
-        ip_blocks,
-        country_locations,
+    Ok(AsnData { blocks })
+}
+
+fn by_name<'a, R: Read + Seek>(
+    archive: &'a mut ZipArchive<R>,
+    name: &str,
+) -> Result<ZipFile<'a>, GeoLoadError> {
+    archive.by_name(name).map_err(|err| match err {
+        ZipError::FileNotFound => GeoLoadError::MissingEntry(name.to_string()),
+        err => GeoLoadError::Zip(err),
     })
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use zip::write::SimpleFileOptions;
+
+    pub(crate) const IPV4_BLOCKS: &str = "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider,is_anycast
+203.0.113.0/24,3017382,3017382,,0,0,
+198.51.100.0/24,6252001,6252001,,0,0,
+";
+
+    const IPV6_BLOCKS: &str = "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider,is_anycast
+2001:db8::/32,3017382,3017382,,0,0,
+";
+
+    pub(crate) const LOCATIONS: &str = "geoname_id,locale_code,continent_code,continent_name,country_iso_code,country_name,is_in_european_union
+3017382,en,EU,Europe,FR,France,1
+6252001,en,NA,\"North America\",US,\"United States\",0
+";
+
+    /// Writes a zip archive with the given `(name, contents)` entries to a fresh temp file.
+    pub(crate) fn write_archive(test_name: &str, entries: &[(&str, &str)]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "tower-ipfilter-{}-{}.zip",
+            test_name,
+            std::process::id()
+        ));
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        for (name, contents) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_extract_networks() {
+        let path = write_archive(
+            "extract",
+            &[
+                (
+                    "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Blocks-IPv4.csv",
+                    IPV4_BLOCKS,
+                ),
+                (
+                    "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Locations-en.csv",
+                    LOCATIONS,
+                ),
+            ],
+        );
+
+        let networks = extract_networks(&path, DEFAULT_LOCALE).unwrap();
+        assert_eq!(networks.v4.len(), 2);
+        assert!(networks.v6.is_empty());
+        let france = networks.v4.get(&"203.0.113.0/24".parse().unwrap()).unwrap();
+        assert_eq!(france.country_name.as_deref(), Some("France"));
+        assert!(france.is_in_european_union);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_ipv6_networks() {
+        let path = write_archive(
+            "extract-ipv6",
+            &[
+                (
+                    "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Blocks-IPv4.csv",
+                    IPV4_BLOCKS,
+                ),
+                (
+                    "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Blocks-IPv6.csv",
+                    IPV6_BLOCKS,
+                ),
+                (
+                    "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Locations-en.csv",
+                    LOCATIONS,
+                ),
+            ],
+        );
+
+        let networks = extract_networks(&path, DEFAULT_LOCALE).unwrap();
+        assert_eq!(networks.v4.len(), 2);
+        let france = networks.v6.get(&"2001:db8::/32".parse().unwrap()).unwrap();
+        assert_eq!(france.country_name.as_deref(), Some("France"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_entry() {
+        let path = write_archive(
+            "missing-entry",
+            &[(
+                "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Locations-en.csv",
+                LOCATIONS,
+            )],
+        );
+
+        match extract_networks(&path, DEFAULT_LOCALE) {
+            Err(GeoLoadError::MissingEntry(name)) => {
+                assert!(name.ends_with("GeoLite2-Country-Blocks-IPv4.csv"))
+            }
+            other => panic!("expected MissingEntry, got {:?}", other.err()),
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_locations_locale() {
+        let locations_de = LOCATIONS
+            .replace(",en,", ",de,")
+            .replace("France", "Frankreich");
+        let archive = |test_name: &str, locales: &[(&str, &str)]| {
+            let mut entries = vec![(
+                "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Blocks-IPv4.csv".to_string(),
+                IPV4_BLOCKS,
+            )];
+            for (locale, contents) in locales {
+                let name = format!(
+                    "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Locations-{}.csv",
+                    locale
+                );
+                entries.push((name, contents));
+            }
+            let entries: Vec<(&str, &str)> = entries
+                .iter()
+                .map(|(name, contents)| (name.as_str(), *contents))
+                .collect();
+            write_archive(test_name, &entries)
+        };
+        let france = |networks: &CountryNetworks| {
+            let location = networks.v4.get(&"203.0.113.0/24".parse().unwrap()).unwrap();
+            location.country_name.clone().unwrap()
+        };
+
+        // A single locations file is used whatever the requested locale.
+        let path = archive("locale-fallback", &[("de", &locations_de)]);
+        assert_eq!(
+            france(&extract_networks(&path, "en").unwrap()),
+            "Frankreich"
+        );
+        assert_eq!(archive_locale(&path, "en").unwrap(), "de");
+        std::fs::remove_file(path).unwrap();
+
+        let path = archive("locale-pick", &[("en", LOCATIONS), ("de", &locations_de)]);
+        assert_eq!(
+            france(&extract_networks(&path, "de").unwrap()),
+            "Frankreich"
+        );
+        assert_eq!(france(&extract_networks(&path, "en").unwrap()), "France");
+        assert_eq!(archive_locale(&path, "en").unwrap(), "en");
+        match extract_networks(&path, "fr") {
+            Err(GeoLoadError::MissingEntry(name)) => assert_eq!(
+                name,
+                "GeoLite2-Country-Locations-fr.csv (available locales: de, en)"
+            ),
+            other => panic!("expected MissingEntry, got {:?}", other.err()),
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
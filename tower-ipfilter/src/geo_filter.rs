@@ -1,14 +1,21 @@
+use arc_swap::ArcSwap;
 use dashmap::DashMap;
 use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use tracing::info;
 
 use crate::{
-    body::{create_geo_access_denied_response, IpResponseBody}, compress::{load_compressed_data, save_compressed_data}, extract::extract_and_parse_csv, network_filter_service::NetworkFilter, types::{CountryLocation, Mode}
+    body::{create_geo_access_denied_response, IpResponseBody}, compress::{cache_file, load_cached, load_compressed_bytes, save_compressed_data, CacheHeader}, error::GeoLoadError, extract::{archive_locale, extract_networks, resolve_block, DEFAULT_LOCALE}, network_filter_service::{FilterDecision, NetworkFilter, ReloadGate}, types::{CountryLocation, CountryNetworks, GeoData, Mode}
 };
 use std::{
-    error::Error,
+    collections::{HashMap, HashSet},
+    io,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
 };
 
 pub trait IpAddrExt: Sized + Send {
@@ -52,10 +59,7 @@ impl IpAddrExt for IpAddr {
         }
     }
     fn is_ipv4(&self) -> bool {
-        match self {
-            IpAddr::V4(_) => true,
-            IpAddr::V6(_) => false,
-        }
+        matches!(self, IpAddr::V4(_))
     }
 }
 
@@ -71,201 +75,1527 @@ impl IpAddrExt for Ipv4Network {
     }
 }
 
+/// Filters requests by the country their address resolves to.
+///
+/// The networks are held in a [`GeoDatabase`], the filter adds the policy on
+/// top, see [`GeoIpv4Filter::database`].
+///
+/// Cloning is cheap: clones share the database, so reloads and changes to the
+/// networks are seen by all of them. Everything else is copied and changed per
+/// clone: the pinned addresses, the overrides, the listed countries and
+/// geoname ids, and the EU and unknown country toggles.
 #[derive(Debug, Clone)]
 pub struct GeoIpv4Filter {
-    pub networks: DashMap<Ipv4Network, CountryLocation>,
-    pub addresses: DashMap<Ipv4Addr, CountryLocation>,
-    pub countries: DashMap<String, bool>,
+    database: Arc<ArcSwap<GeoDatabase>>,
+    /// Per-address locations, these take precedence over the database.
+    ///
+    /// Locations are shared, so prefer [`GeoIpv4Filter::set_ip_country`], which
+    /// takes an owned [`CountryLocation`] as well as an `Arc`.
+    pub addresses: DashMap<Ipv4Addr, Arc<CountryLocation>>,
+    /// Networks set with [`GeoIpv4Filter::override_network`], these win over
+    /// database networks of the same prefix length.
+    overrides: DashMap<Ipv4Network, Arc<CountryLocation>>,
+    /// The countries set with [`GeoIpv4Filter::set_countries`].
+    countries: Listed<String>,
+    /// GeoNames ids listed alongside `countries`, e.g. to target an overseas
+    /// territory without its parent country.
+    geoname_ids: Listed<u32>,
     pub mode: Mode,
+    block_eu: Toggle,
+    allow_only_eu: Toggle,
+    block_unknown_country: Toggle,
+    private_networks: Vec<Ipv4Network>,
+    private_network_policy: PrivateNetworkPolicy,
+    /// The locale of the country names loaded by [`GeoIpv4Filter::reload`].
+    locale: Arc<str>,
+    pub(crate) reload_gate: Arc<ReloadGate>,
 }
 
-impl GeoIpv4Filter {
-    pub fn new(mode: Mode, path_to_data: impl Into<PathBuf>) -> Result<Self, Box<dyn Error>> {
-        let data_path = Path::new("geo_ip_data.bin.gz");
+/// IPv4 networks resolved to their country, without any filtering policy.
+///
+/// This is the read-only data behind a [`GeoIpv4Filter`], see
+/// [`GeoIpv4Filter::database`], and works on its own as a GeoIP lookup, e.g.
+/// to tag analytics events with a country:
+///
+/// ```ignore
+/// let database = GeoDatabase::new("GeoLite2-Country-CSV.zip")?;
+/// let iso_code = database.lookup(ip).and_then(|country| country.country_iso_code.clone());
+/// ```
+///
+/// Only IPv4 networks are loaded, IPv6 addresses resolve to nothing.
+#[derive(Debug, Clone, Default)]
+pub struct GeoDatabase {
+    /// Locations by network address, grouped by prefix length, longest prefix
+    /// first. Networks of the same location share one.
+    v4: Vec<(u8, HashMap<u32, Arc<CountryLocation>>)>,
+}
 
-        let geo_data = if !data_path.exists() {
-            let data = extract_and_parse_csv(&path_to_data.into())?;
-            save_compressed_data(&data, data_path)?;
-            data
-        } else {
-            load_compressed_data(data_path)?
-        };
+impl GeoDatabase {
+    /// Loads a GeoLite2-Country CSV archive, through the same cache as
+    /// [`GeoIpv4Filter::new`].
+    pub fn new(path_to_data: impl Into<PathBuf>) -> Result<Self, GeoLoadError> {
+        Self::new_with_locale(path_to_data, DEFAULT_LOCALE)
+    }
 
-        info!(
-            "Loaded {} ip blocks and {} country locations",
-            geo_data.ip_blocks.len(),
-            geo_data.country_locations.len()
-        );
+    /// Like [`GeoDatabase::new`], reading country names in `locale`, see
+    /// [`GeoIpv4Filter::new_with_locale`].
+    pub fn new_with_locale(
+        path_to_data: impl Into<PathBuf>,
+        locale: &str,
+    ) -> Result<Self, GeoLoadError> {
+        let path_to_data = path_to_data.into();
+        let cache_path = locale_cache_file(&cache_locale(&path_to_data, locale)?);
+        let networks = load_networks(&path_to_data, &cache_path, locale)?;
+        Ok(Self::from_networks(networks))
+    }
 
-        let ip_country_map = DashMap::<Ipv4Network, CountryLocation>::new();
-
-        // add localhost
-        ip_country_map.insert(
-            Ipv4Network::from(Ipv4Addr::new(127, 0, 0, 1)),
-            CountryLocation {
-                geoname_id: 0,
-                locale_code: "NB".to_string(),
-                continent_code: "NA".to_string(),
-                continent_name: "Europe".to_string(),
-                country_iso_code: Some("NO".to_string()),
-                country_name: Some("Norway".to_string()),
-                is_in_european_union: true,
-            },
-        );
+    /// Loads a cache built with [`GeoIpv4Filter::build_cache`], see
+    /// [`GeoIpv4Filter::from_compressed_bytes`].
+    pub fn from_compressed_bytes(bytes: &[u8]) -> Result<Self, GeoLoadError> {
+        let networks = load_compressed_bytes::<CountryNetworks>(bytes)?.v4;
+        info!("Loaded {} networks", networks.len());
+        Ok(Self::from_networks(networks))
+    }
+
+    /// Loads the IPv4 networks of a MaxMind country database such as
+    /// `GeoLite2-Country.mmdb`.
+    #[cfg(feature = "mmdb")]
+    pub fn from_mmdb(path: impl AsRef<Path>) -> Result<Self, GeoLoadError> {
+        let networks = crate::mmdb::load_mmdb_networks(path.as_ref())?;
+        info!("Loaded {} networks from {}", networks.len(), path.as_ref().display());
+        Ok(Self::from_networks(networks))
+    }
 
-        for block in geo_data.ip_blocks {
-            if let Some(geoname_id) = block.geoname_id {
-                if let Ok(network) = block.network.parse() {
-                    if let Some(country) = geo_data.country_locations.get(&geoname_id) {
-                        ip_country_map.insert(network, country.clone());
-                    } else {
-                        println!("No country found for geoname_id: {}", geoname_id);
-                    }
+    /// Creates a database over networks that were already resolved to countries.
+    pub fn from_networks(networks: DashMap<Ipv4Network, CountryLocation>) -> Self {
+        let mut shared: HashMap<u32, Arc<CountryLocation>> = HashMap::new();
+        let mut database = Self::default();
+        for (network, country) in networks {
+            let country = match shared.get(&country.geoname_id) {
+                Some(existing) if **existing == country => existing.clone(),
+                _ => {
+                    let country = Arc::new(country);
+                    shared.insert(country.geoname_id, country.clone());
+                    country
                 }
+            };
+            database.insert(network, country);
+        }
+        database
+    }
+
+    /// Returns the location of the most specific network containing `ip`.
+    pub fn lookup(&self, ip: IpAddr) -> Option<&CountryLocation> {
+        match ip {
+            IpAddr::V4(ip) => self.longest_match(ip).map(|(_, country)| country.as_ref()),
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    /// Iterates over the networks and their locations, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (Ipv4Network, &CountryLocation)> + '_ {
+        self.v4.iter().flat_map(|(prefix, networks)| {
+            networks.iter().map(move |(network, country)| {
+                let network = Ipv4Network::new(Ipv4Addr::from(*network), *prefix).unwrap();
+                (network, country.as_ref())
+            })
+        })
+    }
+
+    /// The number of networks.
+    pub fn len(&self) -> usize {
+        self.v4.iter().map(|(_, networks)| networks.len()).sum()
+    }
+
+    /// Whether there are no networks, so every lookup returns `None`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most specific network containing `ip`, as its prefix length and location.
+    fn longest_match(&self, ip: Ipv4Addr) -> Option<(u8, &Arc<CountryLocation>)> {
+        let ip = u32::from(ip);
+        self.v4.iter().find_map(|(prefix, networks)| {
+            let network = ip & u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+            networks.get(&network).map(|country| (*prefix, country))
+        })
+    }
+
+    fn insert(&mut self, network: Ipv4Network, country: Arc<CountryLocation>) {
+        let prefix = network.prefix();
+        let index = match self.v4.binary_search_by(|(existing, _)| prefix.cmp(existing)) {
+            Ok(index) => index,
+            Err(index) => {
+                self.v4.insert(index, (prefix, HashMap::new()));
+                index
             }
+        };
+        self.v4[index].1.insert(network.network().into(), country);
+    }
+
+    fn remove(&mut self, network: Ipv4Network) -> bool {
+        let prefix = network.prefix();
+        self.v4
+            .iter_mut()
+            .find(|(existing, _)| *existing == prefix)
+            .is_some_and(|(_, networks)| networks.remove(&network.network().into()).is_some())
+    }
+
+    /// Keeps only the networks for which `keep` returns `true`.
+    fn retain(&mut self, mut keep: impl FnMut(Ipv4Network) -> bool) {
+        for (prefix, networks) in &mut self.v4 {
+            let prefix = *prefix;
+            networks.retain(|network, _| {
+                keep(Ipv4Network::new(Ipv4Addr::from(*network), prefix).unwrap())
+            });
         }
+    }
+}
+
+/// How [`GeoIpv4Filter`] decides on addresses in its private networks, see
+/// [`GeoIpv4Filter::private_network_policy`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PrivateNetworkPolicy {
+    /// Let the addresses through, whatever the mode.
+    Allow,
+    /// Block the addresses, whatever the mode.
+    Deny,
+    /// Look the addresses up like any other. Few datasets have countries for
+    /// these ranges, so they are usually treated as unknown addresses.
+    #[default]
+    Classify,
+}
+
+/// The private ranges of RFC 1918, the shared address space of carrier-grade
+/// NAT (RFC 6598) and loopback.
+fn default_private_networks() -> Vec<Ipv4Network> {
+    ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "100.64.0.0/10", "127.0.0.0/8"]
+        .iter()
+        .map(|network| network.parse().expect("valid network"))
+        .collect()
+}
 
-        Ok(Self {
-            networks: ip_country_map,
+/// A flag that can be flipped through `&self`; clones copy the current value.
+#[derive(Debug, Default)]
+struct Toggle(AtomicBool);
+
+impl Toggle {
+    fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, value: bool) {
+        self.0.store(value, Ordering::Relaxed)
+    }
+}
+
+impl Clone for Toggle {
+    fn clone(&self) -> Self {
+        Self(AtomicBool::new(self.get()))
+    }
+}
+
+/// A set that is replaced as a whole through `&self`, so readers see either the
+/// old or the new set; clones copy the current set.
+#[derive(Debug, Default)]
+struct Listed<T>(ArcSwap<HashSet<T>>);
+
+impl<T> Listed<T> {
+    fn load(&self) -> Arc<HashSet<T>> {
+        self.0.load_full()
+    }
+
+    fn store(&self, set: HashSet<T>) {
+        self.0.store(Arc::new(set))
+    }
+}
+
+impl<T> Clone for Listed<T> {
+    fn clone(&self) -> Self {
+        Self(ArcSwap::new(self.load()))
+    }
+}
+
+impl GeoIpv4Filter {
+    pub fn new(mode: Mode, path_to_data: impl Into<PathBuf>) -> Result<Self, GeoLoadError> {
+        Self::new_with_locale(mode, path_to_data, DEFAULT_LOCALE)
+    }
+
+    /// Like [`GeoIpv4Filter::new`], reading country names in `locale`, e.g.
+    /// `"de"` for the `GeoLite2-Country-Locations-de.csv` file.
+    ///
+    /// Countries passed to [`GeoIpv4Filter::set_countries`] must then use the
+    /// names in that locale. An archive with a single locations file in
+    /// another locale is loaded anyway, with a warning.
+    pub fn new_with_locale(
+        mode: Mode,
+        path_to_data: impl Into<PathBuf>,
+        locale: &str,
+    ) -> Result<Self, GeoLoadError> {
+        let database = GeoDatabase::new_with_locale(path_to_data, locale)?;
+
+        let mut filter = Self::from_database(mode, database);
+        filter.locale = locale.into();
+        Ok(filter)
+    }
+
+    /// Creates a filter from a cache built with [`GeoIpv4Filter::build_cache`],
+    /// without touching the filesystem.
+    ///
+    /// Meant for caches compiled into the binary for read-only deployments:
+    ///
+    /// ```ignore
+    /// static GEO_DATA: &[u8] = include_bytes!("../geo_ip_data.bin.gz");
+    /// let filter = GeoIpv4Filter::from_compressed_bytes(Mode::WhiteList, GEO_DATA)?;
+    /// ```
+    ///
+    /// The cache must come from the same crate version's format, otherwise
+    /// [`GeoLoadError::StaleCache`] is returned.
+    pub fn from_compressed_bytes(mode: Mode, bytes: &[u8]) -> Result<Self, GeoLoadError> {
+        let database = GeoDatabase::from_compressed_bytes(bytes)?;
+        Ok(Self::from_database(mode, database))
+    }
+
+    /// Parses the CSV archive at `path_to_data` into a cache at `cache_path`,
+    /// reading country names in `locale`, e.g. as a build step ahead of
+    /// [`GeoIpv4Filter::from_compressed_bytes`].
+    pub fn build_cache(
+        path_to_data: impl AsRef<Path>,
+        cache_path: impl AsRef<Path>,
+        locale: &str,
+    ) -> Result<(), GeoLoadError> {
+        let path_to_data = path_to_data.as_ref();
+        let header = CacheHeader::for_source(path_to_data)?;
+        let networks = extract_networks(path_to_data, locale)?;
+        save_compressed_data(&networks, &header, cache_path.as_ref())
+    }
+
+    /// Creates a filter from a MaxMind country database such as `GeoLite2-Country.mmdb`.
+    ///
+    /// The IPv4 networks are loaded up front, so lookups behave exactly as with
+    /// the CSV data.
+    #[cfg(feature = "mmdb")]
+    pub fn from_mmdb(mode: Mode, path: impl AsRef<Path>) -> Result<Self, GeoLoadError> {
+        Ok(Self::from_database(mode, GeoDatabase::from_mmdb(path)?))
+    }
+
+    /// Creates a filter over networks that were already resolved to countries.
+    pub fn from_networks(mode: Mode, networks: DashMap<Ipv4Network, CountryLocation>) -> Self {
+        Self::from_database(mode, GeoDatabase::from_networks(networks))
+    }
+
+    /// Creates a filter over a database loaded on its own.
+    pub fn from_database(mode: Mode, database: GeoDatabase) -> Self {
+        Self {
+            database: Arc::new(ArcSwap::from_pointee(database)),
             addresses: DashMap::new(),
-            countries: DashMap::new(),
+            overrides: DashMap::new(),
+            countries: Listed::default(),
+            geoname_ids: Listed::default(),
             mode,
-        })
+            block_eu: Toggle::default(),
+            allow_only_eu: Toggle::default(),
+            block_unknown_country: Toggle::default(),
+            private_networks: default_private_networks(),
+            private_network_policy: PrivateNetworkPolicy::default(),
+            locale: DEFAULT_LOCALE.into(),
+            reload_gate: Arc::default(),
+        }
+    }
+
+    /// Reloads the network data from `path_to_data`, see [`GeoIpv4Filter::replace_networks`].
+    ///
+    /// Country names are read in the locale the filter was created with.
+    /// Requests are held back from the start of loading, including reading
+    /// the archive or cache, until the new data is live.
+    pub fn reload(&self, path_to_data: impl Into<PathBuf>) -> Result<(), GeoLoadError> {
+        let _update = self.reload_gate.begin();
+        let path_to_data = path_to_data.into();
+        let cache_path = locale_cache_file(&cache_locale(&path_to_data, &self.locale)?);
+
+        let networks = load_networks(&path_to_data, &cache_path, &self.locale)?;
+
+        self.replace_networks(networks);
+        Ok(())
+    }
+
+    /// Atomically swaps in new network data for this filter and all its clones.
+    ///
+    /// Lookups in progress finish against the old data. The
+    /// [`Filter`](crate::network_filter_service::Filter) service holds back new
+    /// requests in `poll_ready` while the new database is built and until the
+    /// swap is done. Networks added with [`GeoIpv4Filter::add_network`] are
+    /// replaced too.
+    pub fn replace_networks(&self, networks: DashMap<Ipv4Network, CountryLocation>) {
+        let _update = self.reload_gate.begin();
+        let database = GeoDatabase::from_networks(networks);
+        self.database.store(Arc::new(database));
     }
 
+    /// The database currently in use.
+    ///
+    /// This is a snapshot: later reloads and changes to the networks swap in a
+    /// new database and leave the returned one as it is.
+    pub fn database(&self) -> Arc<GeoDatabase> {
+        self.database.load_full()
+    }
+
+    /// Layers `extra` on top of the current networks, e.g. a small set of
+    /// corrections for ranges the base dataset gets wrong.
+    ///
+    /// Networks from `extra` replace identical networks and any networks they
+    /// contain, so later sources win. Blocks without a known country are skipped,
+    /// as are IPv6 networks.
+    pub fn merge_networks(&self, extra: GeoData) {
+        let _update = self.reload_gate.begin();
+        let resolved: Vec<(Ipv4Network, Arc<CountryLocation>)> = extra
+            .ip_blocks
+            .iter()
+            .filter_map(|block| resolve_block(block, &extra.country_locations))
+            .map(|(network, country)| (network, Arc::new(country)))
+            .collect();
+        self.update_database(|database| {
+            for (network, country) in &resolved {
+                database.retain(|existing| {
+                    existing.prefix() < network.prefix() || !network.contains(existing.network())
+                });
+                database.insert(*network, country.clone());
+            }
+        });
+    }
+
+    /// Applies `update` to a copy of the database and swaps it in, for this
+    /// filter and all its clones. Requests are held back until it's done, as
+    /// with [`GeoIpv4Filter::replace_networks`].
+    ///
+    /// Copying costs as much as the database is large, so callers take their
+    /// changes in batches.
+    fn update_database(&self, mut update: impl FnMut(&mut GeoDatabase)) {
+        let _update = self.reload_gate.begin();
+        self.database.rcu(|database| {
+            let mut database = GeoDatabase::clone(database);
+            update(&mut database);
+            database
+        });
+    }
+
+    /// Returns the location of `ip`, checking `addresses` before the database.
+    ///
+    /// Of several networks containing `ip`, the one with the longest prefix wins,
+    /// with overrides winning ties, see [`GeoIpv4Filter::override_network`].
+    /// The location is shared with the filter, clone it to get an owned copy.
+    pub fn country_for_ip(&self, ip: &Ipv4Addr) -> Option<Arc<CountryLocation>> {
+        self.country_in(&self.database.load(), ip)
+    }
+
+    /// Returns an owned copy of the location of `ip`, see
+    /// [`GeoIpv4Filter::country_for_ip`].
+    #[deprecated(note = "use `country_for_ip`, which shares the location instead of copying it")]
     pub async fn get_country_for_ip(&self, ip: &Ipv4Addr) -> Option<CountryLocation> {
-        let mut country = None;
+        self.country_for_ip(ip).as_deref().cloned()
+    }
 
+    fn country_in(&self, database: &GeoDatabase, ip: &Ipv4Addr) -> Option<Arc<CountryLocation>> {
         if let Some(location) = self.addresses.get(ip) {
             return Some(location.clone());
         }
 
-        for kv in self.networks.iter() {
+        // Networks may nest, e.g. a /24 carved out of a /16 for another country,
+        // so the most specific match wins. Overrides win ties with the database.
+        let mut best = database
+            .longest_match(*ip)
+            .map(|(prefix, country)| (prefix, country.clone()));
+        for kv in self.overrides.iter() {
             let (network, location) = kv.pair();
-            if network.contains(*ip) {
-                country = Some(location.clone());
-                break;
+            let as_specific = best
+                .as_ref()
+                .is_none_or(|(prefix, _)| network.prefix() >= *prefix);
+            if as_specific && network.contains(*ip) {
+                best = Some((network.prefix(), location.clone()));
             }
         }
-        country
+        best.map(|(_, country)| country)
     }
 
-    pub async fn add_ip(&self, ip: Ipv4Addr) {
-        if let Some(country) = self.get_country_for_ip(&ip).await {
-            self.addresses.insert(ip, country.clone());
+    /// Pins `ip` to the country it currently resolves to.
+    ///
+    /// Returns `false`, adding nothing, when the address doesn't resolve to a
+    /// country; use [`GeoIpv4Filter::set_ip_country`] to classify it by hand.
+    pub async fn add_ip(&self, ip: Ipv4Addr) -> bool {
+        match self.country_for_ip(&ip) {
+            Some(country) => {
+                self.addresses.insert(ip, country);
+                true
+            }
+            None => false,
         }
     }
 
-    pub fn remove_ip(&self, ip: Ipv4Addr) {
-        self.addresses.remove(&ip);
+    /// Pins `ip` to `country`, whether or not it resolves through the network data.
+    ///
+    /// Use this to correct addresses the dataset gets wrong; the entry takes
+    /// precedence over every network and survives reloads. Undo it with
+    /// [`GeoIpv4Filter::remove_ip`].
+    pub fn set_ip_country(&self, ip: Ipv4Addr, country: impl Into<Arc<CountryLocation>>) {
+        self.addresses.insert(ip, country.into());
+    }
+
+    /// Removes the entry for `ip`, returning whether there was one.
+    ///
+    /// Like the other lookups this never waits, so it isn't async; the
+    /// [`NetworkFilter::unblock`] path is the async equivalent.
+    pub fn remove_ip(&self, ip: Ipv4Addr) -> bool {
+        self.addresses.remove(&ip).is_some()
     }
 
-    pub async fn add_network(&self, network: Ipv4Network) {
-        if let Some(country) = self.get_country_for_ip(&network.network()).await {
-            self.networks.insert(network, country.clone());
-            tracing::info!("Added network: {} from country: {}", network, country.country_name.unwrap());
+    /// Adds `network` with the country its network address resolves to.
+    ///
+    /// Returns `false`, adding nothing, when that address doesn't resolve to a
+    /// country. Each change copies the database, so add many networks with
+    /// [`GeoIpv4Filter::add_networks_bulk`].
+    ///
+    /// The network becomes part of the database, so the next reload or
+    /// [`GeoIpv4Filter::replace_networks`] drops it. Use
+    /// [`GeoIpv4Filter::override_network`] for networks that should stay.
+    pub async fn add_network(&self, network: Ipv4Network) -> bool {
+        match self.country_for_ip(&network.network()) {
+            Some(country) => {
+                let name = &country.country_name;
+                tracing::info!("Added network: {} from country: {:?}", network, name);
+                self.update_database(|database| database.insert(network, country.clone()));
+                true
+            }
+            None => false,
         }
     }
 
-    pub fn remove_network(&self, network: Ipv4Network) {
-        self.networks.remove(&network);
+    /// Resolves every address in `network` to `country`, e.g. to correct an ISP
+    /// range the dataset mislabels.
+    ///
+    /// Longest-prefix matching still applies, so a more specific network from
+    /// the dataset wins; of equally specific networks the override does. Unlike
+    /// the networks added with [`GeoIpv4Filter::add_network`], overrides survive
+    /// reloads. Undo it with [`GeoIpv4Filter::remove_override`].
+    pub fn override_network(&self, network: Ipv4Network, country: CountryLocation) {
+        tracing::info!("Overriding network: {} with country: {:?}", network, country.country_name);
+        self.overrides.insert(network, Arc::new(country));
+    }
+
+    /// Removes the override for `network`, returning whether there was one.
+    pub fn remove_override(&self, network: Ipv4Network) -> bool {
+        self.overrides.remove(&network).is_some()
+    }
+
+    /// Adds many networks at once, replacing any existing entries for the same networks.
+    ///
+    /// Like [`GeoIpv4Filter::add_network`], these last until the next reload.
+    pub fn add_networks_bulk(
+        &self,
+        networks: impl IntoIterator<Item = (Ipv4Network, CountryLocation)>,
+    ) {
+        let networks: Vec<(Ipv4Network, Arc<CountryLocation>)> = networks
+            .into_iter()
+            .map(|(network, country)| (network, Arc::new(country)))
+            .collect();
+        self.update_database(|database| {
+            for (network, country) in &networks {
+                database.insert(*network, country.clone());
+            }
+        });
+    }
+
+    /// Removes `network`, returning whether it was listed, see
+    /// [`GeoIpv4Filter::remove_ip`].
+    ///
+    /// Each change copies the database, so remove many networks with
+    /// [`GeoIpv4Filter::remove_networks_bulk`].
+    pub fn remove_network(&self, network: Ipv4Network) -> bool {
+        self.remove_networks_bulk([network]) == 1
     }
 
+    /// Removes many networks at once, returning how many were listed.
+    pub fn remove_networks_bulk(&self, networks: impl IntoIterator<Item = Ipv4Network>) -> usize {
+        let networks: Vec<Ipv4Network> = networks.into_iter().collect();
+        let mut removed = 0;
+        self.update_database(|database| {
+            removed = networks.iter().filter(|network| database.remove(**network)).count();
+        });
+        removed
+    }
+
+    /// Replaces the listed countries, see [`GeoIpv4Filter::blocked_countries`].
+    ///
+    /// The new list is swapped in at once for this filter, requests see either
+    /// the old or the new list and are held back while it's built.
     pub fn set_countries(&self, countries: Vec<String>) {
-        self.countries.clear();
+        let _update = self.reload_gate.begin();
         tracing::info!("Setting countries: {:?}, mode: {}", countries, self.mode);
+        let countries: HashSet<String> = countries.into_iter().collect();
+        self.countries.store(countries);
+    }
+
+    /// Like [`GeoIpv4Filter::set_countries`], but only if every name is a
+    /// country in the loaded data, to catch typos at startup.
+    ///
+    /// Names are matched ignoring surrounding whitespace and case, then stored
+    /// as spelled in the data, so `" united states"` lists `"United States"`.
+    /// Otherwise returns the unrecognized names as given and leaves the current
+    /// countries untouched.
+    pub fn try_set_countries(&self, countries: Vec<String>) -> Result<(), Vec<String>> {
+        let known = self.known_countries();
+        let mut resolved = Vec::with_capacity(countries.len());
+        let mut unknown = Vec::new();
         for country in countries {
-            self.countries.insert(country, true);
-            
+            match known.get(&country.trim().to_lowercase()) {
+                Some(name) => resolved.push(name.clone()),
+                None => unknown.push(country),
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(unknown);
         }
+        self.set_countries(resolved);
+        Ok(())
+    }
+
+    /// The country names found in the loaded data, keyed by their lowercase form.
+    fn known_countries(&self) -> HashMap<String, String> {
+        let mut known = HashMap::new();
+        let mut add = |country: &CountryLocation| {
+            if let Some(name) = &country.country_name {
+                known.insert(name.to_lowercase(), name.clone());
+            }
+        };
+        self.database.load().iter().for_each(|(_, country)| add(country));
+        self.overrides.iter().for_each(|kv| add(kv.value()));
+        self.addresses.iter().for_each(|kv| add(kv.value()));
+        known
+    }
+
+    /// Sets the countries from a file with one country per line, see
+    /// [`GeoIpv4Filter::set_countries`]. Blank lines and `#` comments are ignored.
+    pub fn set_countries_from_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let contents = std::fs::read_to_string(path)?;
+        self.set_countries(parse_countries(&contents));
+        Ok(())
+    }
+
+    /// Lists locations by their GeoNames id, see [`CountryLocation::geoname_id`].
+    ///
+    /// The ids apply alongside the names from [`GeoIpv4Filter::set_countries`]:
+    /// a location listed in either is blocked in blacklist mode and allowed in
+    /// whitelist mode.
+    pub fn set_blocked_geoname_ids(&self, geoname_ids: Vec<u32>) {
+        let _update = self.reload_gate.begin();
+        tracing::info!("Setting geoname ids: {:?}, mode: {}", geoname_ids, self.mode);
+        let geoname_ids: HashSet<u32> = geoname_ids.into_iter().collect();
+        self.geoname_ids.store(geoname_ids);
+    }
+
+    /// Returns the countries set with [`GeoIpv4Filter::set_countries`], sorted.
+    ///
+    /// These are blocked in blacklist mode and the only ones allowed in
+    /// whitelist mode.
+    pub fn blocked_countries(&self) -> Vec<String> {
+        let mut countries: Vec<String> = self.countries.load().iter().cloned().collect();
+        countries.sort();
+        countries
+    }
+
+    /// Returns the ids set with [`GeoIpv4Filter::set_blocked_geoname_ids`], sorted.
+    pub fn blocked_geoname_ids(&self) -> Vec<u32> {
+        let mut geoname_ids: Vec<u32> = self.geoname_ids.load().iter().copied().collect();
+        geoname_ids.sort_unstable();
+        geoname_ids
+    }
+
+    /// Blocks every location in the European Union, whatever the mode, based
+    /// on [`CountryLocation::is_in_european_union`].
+    pub fn block_eu(&self, block: bool) {
+        self.block_eu.set(block);
+    }
+
+    /// Blocks every location outside the European Union, whatever the mode.
+    ///
+    /// In blacklist mode EU countries are still checked against the list; in
+    /// whitelist mode they are all allowed, so exactly the EU gets through.
+    pub fn allow_only_eu(&self, allow_only: bool) {
+        self.allow_only_eu.set(allow_only);
+    }
+
+    /// Blocks addresses whose network resolves to a location without a country,
+    /// whatever the mode, such as the anonymous proxy rows in GeoLite2.
+    ///
+    /// Addresses outside every known network are not affected, see
+    /// [`GeoIpv4Filter::is_ip_blocked`]. When off, these locations are treated
+    /// like any other unlisted location.
+    pub fn block_unknown_country(&self, block: bool) {
+        self.block_unknown_country.set(block);
+    }
+
+    /// Switches the filter to the strictest policy: only addresses resolving to
+    /// one of `countries` get through.
+    ///
+    /// Sets whitelist mode, replaces the country list, clears the geoname ids,
+    /// turns off the EU toggles and blocks unknown countries. Serve it through
+    /// a layer built with [`FilterLayerBuilder::strict`][strict], which pins the
+    /// missing address policy to blocking, and requests are decided as follows:
+    ///
+    /// | The client address                       | Outcome                         |
+    /// |------------------------------------------|---------------------------------|
+    /// | resolves to one of `countries`           | allowed                         |
+    /// | resolves to another country              | blocked                         |
+    /// | resolves to a location without a country | blocked, `"unknown country"`    |
+    /// | is outside every known network           | blocked                         |
+    /// | is missing from the request              | blocked by the [strict] layer   |
+    ///
+    /// Addresses pinned with [`GeoIpv4Filter::set_ip_country`] resolve to their
+    /// pinned country.
+    ///
+    /// [strict]: crate::network_filter_service::FilterLayerBuilder::strict
+    ///
+    /// ```ignore
+    /// let filter = GeoIpv4Filter::new(Mode::WhiteList, "GeoLite2-Country-CSV.zip")?
+    ///     .strict_whitelist(vec!["Norway".to_string()]);
+    /// let layer = FilterLayer::builder(Arc::new(filter)).strict().build();
+    /// ```
+    pub fn strict_whitelist(mut self, countries: Vec<String>) -> Self {
+        self.mode = Mode::WhiteList;
+        self.set_countries(countries);
+        self.geoname_ids.store(HashSet::new());
+        self.block_eu(false);
+        self.allow_only_eu(false);
+        self.block_unknown_country(true);
+        self
+    }
+
+    /// Decides on addresses in the private networks by `policy` instead of
+    /// looking up their country, see [`GeoIpv4Filter::private_networks`].
+    ///
+    /// The default, [`PrivateNetworkPolicy::Classify`], looks them up like any
+    /// other address. The policy also applies to addresses pinned with
+    /// [`GeoIpv4Filter::set_ip_country`].
+    pub fn private_network_policy(mut self, policy: PrivateNetworkPolicy) -> Self {
+        self.private_network_policy = policy;
+        self
+    }
+
+    /// Replaces the networks [`GeoIpv4Filter::private_network_policy`] applies
+    /// to. They default to the RFC 1918 ranges, the carrier-grade NAT range
+    /// `100.64.0.0/10` and loopback `127.0.0.0/8`.
+    pub fn private_networks(mut self, networks: Vec<Ipv4Network>) -> Self {
+        self.private_networks = networks;
+        self
     }
 
     pub async fn is_country_blocked(&self, country: &str) -> bool {
         match self.mode {
-            Mode::BlackList => self.countries.contains_key(country),
-            Mode::WhiteList => !self.countries.contains_key(country),
+            Mode::BlackList => self.countries.load().contains(country),
+            Mode::WhiteList => !self.countries.load().contains(country),
         }
     }
 
     pub async fn is_ip_blocked(&self, ip: &Ipv4Addr) -> bool {
-        if let Some(country) = self.get_country_for_ip(ip).await {
-            let name = country.country_name.unwrap();
-            let is_blocked = self.is_country_blocked(&name).await;
-            if is_blocked {
-                tracing::warn!("Blocked ip: {} from country: {}", ip, name);
-                return true;
-            } else {
-                tracing::debug!("Allowed ip: {} from country: {}", ip, name);
-                return false;
-            }
-            
-        } else {
-            false
+        self.is_ip_blocked_sync(ip)
+    }
+
+    /// The same check as [`GeoIpv4Filter::is_ip_blocked`], for callers outside
+    /// an async context. Lookups never wait on I/O, so this doesn't block.
+    pub fn is_ip_blocked_sync(&self, ip: &Ipv4Addr) -> bool {
+        self.decide_ip(ip).blocked
+    }
+
+    /// Decides on `ip`, IPv6 addresses have no known location.
+    fn decide_addr(&self, ip: IpAddr) -> FilterDecision {
+        match ip {
+            IpAddr::V4(ip) => self.decide_ip(&ip),
+            _ => FilterDecision::new(self.mode == Mode::WhiteList),
         }
     }
-}
 
-impl NetworkFilter for GeoIpv4Filter {
-    fn block(
+    fn decide_ip(&self, ip: &Ipv4Addr) -> FilterDecision {
+        self.decide_in(&self.database.load(), ip)
+    }
+
+    fn decide_in(
         &self,
-        ip: impl IpAddrExt,
-        network: bool,
-    ) -> impl std::future::Future<Output = ()> + Send {
-        async move {
-            if network {
-                match ip.to_network() {
-                    IpNetwork::V4(ip) => {
-                        self.add_network(ip)
-                            .await;
-                    }
-                    _ => {}
-                }
+        database: &GeoDatabase,
+        ip: &Ipv4Addr,
+    ) -> FilterDecision {
+        let private = match self.private_network_policy {
+            PrivateNetworkPolicy::Allow => Some(false),
+            PrivateNetworkPolicy::Deny => Some(true),
+            PrivateNetworkPolicy::Classify => None,
+        };
+        if let Some(blocked) = private {
+            if self.private_networks.iter().any(|network| network.contains(*ip)) {
+                return FilterDecision::new(blocked).with_reason("private network");
+            }
+        }
+
+        if let Some(country) = self.country_in(database, ip) {
+            let name = country.country_name.as_deref();
+            let is_unknown = name.is_none() && country.country_iso_code.is_none();
+            // A location is listed by its geoname_id or by its country name.
+            let reason = if self.geoname_ids.load().contains(&country.geoname_id) {
+                Some(format!("geoname_id {}", country.geoname_id))
             } else {
-                match ip.to_ip_addr() {
-                    IpAddr::V4(ip) => {
-                        self.add_ip(ip)
-                            .await;
-                    }
-                    _ => {}
+                name.filter(|name| self.countries.load().contains(*name))
+                    .map(|name| format!("country {}", name))
+            };
+            let (is_blocked, reason) = match self.mode {
+                _ if is_unknown && self.block_unknown_country.get() => {
+                    (true, Some("unknown country".to_string()))
+                }
+                _ if country.is_in_european_union && self.block_eu.get() => {
+                    (true, Some("EU country".to_string()))
+                }
+                _ if !country.is_in_european_union && self.allow_only_eu.get() => {
+                    (true, Some("outside the EU".to_string()))
                 }
+                Mode::WhiteList if self.allow_only_eu.get() => (false, reason),
+                Mode::BlackList => (reason.is_some(), reason),
+                Mode::WhiteList => (reason.is_none(), reason),
+            };
+            let mut decision = FilterDecision::new(is_blocked);
+            if let Some(reason) = reason {
+                decision = decision.with_reason(reason);
             }
+            decision.with_country(country)
+        } else {
+            // An address outside every known network can't be on a whitelist.
+            FilterDecision::new(self.mode == Mode::WhiteList)
         }
     }
+}
 
-    fn unblock(
-        &self,
-        ip: impl IpAddrExt,
-        network: bool,
-    ) -> impl std::future::Future<Output = ()> + Send {
-        async move {
-            if network {
-                match ip.to_network() {
-                    IpNetwork::V4(ip) => {
-                        self.remove_network(ip);
-                    }
-                    _ => {}
-                }
-            } else {
-                match ip.to_ip_addr() {
-                    IpAddr::V4(ip) => {
-                        self.remove_ip(ip);
-                    }
-                    _ => {}
+/// Keeps a countries file watched, see [`GeoIpv4Filter::watch_countries_file`].
+///
+/// Watching stops when this is dropped.
+#[cfg(feature = "notify")]
+pub struct CountriesWatcher {
+    _watcher: notify::RecommendedWatcher,
+}
+
+#[cfg(feature = "notify")]
+impl GeoIpv4Filter {
+    /// Loads the countries from `path` and reloads them whenever the file
+    /// changes, until the returned watcher is dropped.
+    ///
+    /// The file format is described at [`GeoIpv4Filter::set_countries_from_file`].
+    /// If a reload fails, e.g. because the file was removed, the previous list
+    /// stays. Replace the file atomically (write a temporary file, then rename
+    /// it) so a reload never sees it half-written.
+    ///
+    /// Clones don't share the country list, so this takes the `Arc` the filter
+    /// is served from.
+    pub fn watch_countries_file(
+        self: &Arc<Self>,
+        path: impl Into<PathBuf>,
+    ) -> Result<CountriesWatcher, GeoLoadError> {
+        use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+        let path = path.into();
+        self.set_countries_from_file(&path)?;
+
+        let filter = Arc::downgrade(self);
+        let watched = path.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else { return };
+            let changed = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                && event.paths.iter().any(|path| path.file_name() == watched.file_name());
+            if let (true, Some(filter)) = (changed, filter.upgrade()) {
+                if let Err(err) = filter.set_countries_from_file(&watched) {
+                    let path = watched.display();
+                    tracing::warn!("Failed to reload countries from {}: {}", path, err);
                 }
             }
+        })?;
+        // Watch the directory, replacing the file would end a watch on the file itself.
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        Ok(CountriesWatcher { _watcher: watcher })
+    }
+}
+
+/// Parses one country per line, skipping blank lines and `#` comments.
+fn parse_countries(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The locale to key the cache of `locale` networks from `source_path` by.
+///
+/// That is the locale the archive actually provides, so a fallback to another
+/// locale isn't cached under the requested one. Without a readable archive it
+/// is `locale` itself. Fails for anything but a locale code, as it becomes part
+/// of the cache file name.
+fn cache_locale(source_path: &Path, locale: &str) -> Result<String, GeoLoadError> {
+    let is_locale = |locale: &str| {
+        !locale.is_empty()
+            && locale.len() <= 16
+            && locale.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    };
+    if !is_locale(locale) {
+        return Err(GeoLoadError::InvalidLocale(locale.to_string()));
+    }
+    Ok(archive_locale(source_path, locale)
+        .ok()
+        .filter(|found| is_locale(found))
+        .unwrap_or_else(|| locale.to_string()))
+}
+
+/// The cache of the networks loaded in `locale`, each locale has its own.
+fn locale_cache_file(locale: &str) -> PathBuf {
+    if locale == DEFAULT_LOCALE {
+        cache_file("geo_ip_data")
+    } else {
+        cache_file(&format!("geo_ip_data_{}", locale))
+    }
+}
+
+/// Loads the networks from the cache at `cache_path`, see [`load_cached`].
+fn load_networks(
+    source_path: &Path,
+    cache_path: &Path,
+    locale: &str,
+) -> Result<DashMap<Ipv4Network, CountryLocation>, GeoLoadError> {
+    let parse = |path: &Path| extract_networks(path, locale);
+    let ip_country_map = load_cached(source_path, cache_path, parse)?.v4;
+    info!("Loaded {} networks", ip_country_map.len());
+
+    Ok(ip_country_map)
+}
+
+impl NetworkFilter for GeoIpv4Filter {
+    async fn block(&self, ip: impl IpAddrExt, network: bool) {
+        if network {
+            if let IpNetwork::V4(ip) = ip.to_network() {
+                self.add_network(ip).await;
+            }
+        } else if let IpAddr::V4(ip) = ip.to_ip_addr() {
+            self.add_ip(ip).await;
         }
     }
 
-    fn is_blocked(&self, ip: impl IpAddrExt) -> impl std::future::Future<Output = bool> + Send {
-        async move {
-            match ip.to_ip_addr() {
-                IpAddr::V4(ip) => self.is_ip_blocked(&ip).await,
-                _ => false,
+    async fn unblock(&self, ip: impl IpAddrExt, network: bool) {
+        if network {
+            if let IpNetwork::V4(ip) = ip.to_network() {
+                self.remove_network(ip);
             }
+        } else if let IpAddr::V4(ip) = ip.to_ip_addr() {
+            self.remove_ip(ip);
         }
     }
 
-    fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>>{
+    async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+        match ip.to_ip_addr() {
+            IpAddr::V4(ip) => self.is_ip_blocked(&ip).await,
+            // IPv6 addresses have no known location, like unknown IPv4 addresses.
+            _ => self.mode == Mode::WhiteList,
+        }
+    }
+
+    async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+        self.decide_addr(ip.to_ip_addr())
+    }
+
+    fn decide_now(&self, ip: IpAddr) -> Option<FilterDecision> {
+        Some(self.decide_addr(ip))
+    }
+
+    fn is_blocked_batch(&self, ips: &[IpAddr]) -> Vec<bool> {
+        let database = self.database.load();
+        ips.iter()
+            .map(|ip| match ip {
+                IpAddr::V4(ip) => self.decide_in(&database, ip).blocked,
+                _ => self.mode == Mode::WhiteList,
+            })
+            .collect()
+    }
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.reload_gate.poll_ready(cx)
+    }
+
+    fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
         create_geo_access_denied_response()
     }
+
+    fn mode(&self) -> Option<Mode> {
+        Some(self.mode.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::load_compressed_data;
+    use crate::extract::tests::{write_archive, IPV4_BLOCKS, LOCATIONS};
+
+    fn write_source(test_name: &str) -> PathBuf {
+        write_archive(
+            test_name,
+            &[
+                (
+                    "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Blocks-IPv4.csv",
+                    IPV4_BLOCKS,
+                ),
+                (
+                    "GeoLite2-Country-CSV_20241015/GeoLite2-Country-Locations-en.csv",
+                    LOCATIONS,
+                ),
+            ],
+        )
+    }
+
+    fn cache_path(test_name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "tower-ipfilter-{}-{}.bin",
+            test_name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_corrupt_cache_is_rebuilt() {
+        let source = write_source("corrupt-cache");
+        let cache = cache_path("corrupt-cache");
+        std::fs::write(&cache, b"not a gzip stream").unwrap();
+
+        let networks = load_networks(&source, &cache, DEFAULT_LOCALE).unwrap();
+        assert_eq!(networks.len(), 2);
+
+        // The rewritten cache is valid for the same source.
+        let header = CacheHeader::for_source(&source).unwrap();
+        let cached: CountryNetworks = load_compressed_data(&cache, Some(&header)).unwrap();
+        assert_eq!(cached.v4.len(), 2);
+        for entry in cached.v4.iter() {
+            assert_eq!(networks.get(entry.key()).as_deref(), Some(entry.value()));
+        }
+
+        std::fs::remove_file(source).unwrap();
+        std::fs::remove_file(cache).unwrap();
+    }
+
+    #[test]
+    fn test_stale_cache_is_rebuilt() {
+        let source = write_source("stale-cache");
+        let cache = cache_path("stale-cache");
+
+        let stale = CountryNetworks::default();
+        let other_source = write_archive("stale-cache-other", &[("empty.csv", "")]);
+        let other_header = CacheHeader::for_source(&other_source).unwrap();
+        save_compressed_data(&stale, &other_header, &cache).unwrap();
+        assert!(matches!(
+            load_compressed_data::<CountryNetworks>(&cache, Some(&CacheHeader::for_source(&source).unwrap())),
+            Err(GeoLoadError::StaleCache)
+        ));
+
+        let networks = load_networks(&source, &cache, DEFAULT_LOCALE).unwrap();
+        assert_eq!(networks.len(), 2);
+
+        std::fs::remove_file(source).unwrap();
+        std::fs::remove_file(other_source).unwrap();
+        std::fs::remove_file(cache).unwrap();
+    }
+
+    #[test]
+    fn test_cache_locale() {
+        let source = write_source("cache-locale");
+        assert_eq!(cache_locale(&source, "en").unwrap(), "en");
+        // The archive only has English names, so those are what gets cached.
+        assert_eq!(cache_locale(&source, "fr").unwrap(), "en");
+        for invalid in ["", "../en", "en/..", "C:\\en", "en.csv"] {
+            assert!(
+                matches!(cache_locale(&source, invalid), Err(GeoLoadError::InvalidLocale(_))),
+                "{}",
+                invalid
+            );
+        }
+        std::fs::remove_file(&source).unwrap();
+
+        assert_eq!(cache_locale(&source, "pt-BR").unwrap(), "pt-BR");
+    }
+
+    #[test]
+    fn test_cache_used_without_source() {
+        let source = write_source("cache-only");
+        let cache = cache_path("cache-only");
+        load_networks(&source, &cache, DEFAULT_LOCALE).unwrap();
+        std::fs::remove_file(&source).unwrap();
+
+        let networks = load_networks(&source, &cache, DEFAULT_LOCALE).unwrap();
+        assert_eq!(networks.len(), 2);
+
+        std::fs::remove_file(cache).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_from_compressed_bytes() {
+        let source = write_source("compressed-bytes");
+        let cache = cache_path("compressed-bytes");
+        GeoIpv4Filter::build_cache(&source, &cache, DEFAULT_LOCALE).unwrap();
+        let bytes = std::fs::read(&cache).unwrap();
+        std::fs::remove_file(source).unwrap();
+        std::fs::remove_file(cache).unwrap();
+
+        let filter = GeoIpv4Filter::from_compressed_bytes(Mode::BlackList, &bytes).unwrap();
+        let country = filter.country_for_ip(&Ipv4Addr::new(203, 0, 113, 1));
+        assert_eq!(country.unwrap().country_name.as_deref(), Some("France"));
+        assert_eq!(filter.database().len(), 2);
+        // Loopback is left to the private network policy, not given a country.
+        assert!(filter.country_for_ip(&Ipv4Addr::LOCALHOST).is_none());
+
+        assert!(GeoIpv4Filter::from_compressed_bytes(Mode::BlackList, b"not a cache").is_err());
+    }
+
+    fn location(geoname_id: u32, name: &str) -> CountryLocation {
+        CountryLocation {
+            geoname_id,
+            locale_code: "en".to_string(),
+            continent_code: "EU".to_string(),
+            continent_name: "Europe".to_string(),
+            country_iso_code: None,
+            country_name: Some(name.to_string()),
+            is_in_european_union: false,
+        }
+    }
+
+    fn block(network: &str, geoname_id: Option<u32>) -> crate::types::IpBlock {
+        crate::types::IpBlock {
+            network: network.to_string(),
+            geoname_id,
+            registered_country_geoname_id: None,
+            represented_country_geoname_id: None,
+            is_anonymous_proxy: false,
+            is_satellite_provider: false,
+            is_anycast: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_networks_bulk() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, DashMap::new());
+        filter.set_countries(vec!["France".to_string()]);
+
+        filter.add_networks_bulk((0..10_000u32).map(|i| {
+            let network = Ipv4Network::new(Ipv4Addr::from(0x0a00_0000 + (i << 8)), 24).unwrap();
+            (network, location(1, "France"))
+        }));
+
+        assert_eq!(filter.database().len(), 10_000);
+        assert!(filter.is_ip_blocked(&Ipv4Addr::new(10, 0, 42, 1)).await);
+        assert!(!filter.is_ip_blocked(&Ipv4Addr::new(11, 0, 0, 1)).await);
+
+        let removed = filter.remove_networks_bulk((0..5_000u32).map(|i| {
+            Ipv4Network::new(Ipv4Addr::from(0x0a00_0000 + (i << 8)), 24).unwrap()
+        }));
+        assert_eq!(removed, 5_000);
+        assert_eq!(filter.database().len(), 5_000);
+        assert!(!filter.is_ip_blocked(&Ipv4Addr::new(10, 0, 42, 1)).await);
+        assert!(!filter.reload_gate.is_reloading());
+    }
+
+    #[test]
+    fn test_failed_reload_releases_gate() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, DashMap::new());
+        filter.set_countries(vec!["France".to_string()]);
+        assert!(filter.reload("/nonexistent/GeoLite2-Country-CSV.zip").is_err());
+        assert!(!filter.reload_gate.is_reloading());
+    }
+
+    #[tokio::test]
+    async fn test_block_by_geoname_id() {
+        let networks = DashMap::new();
+        networks.insert("203.0.113.0/24".parse().unwrap(), location(3017382, "France"));
+        // An overseas territory, listed under its own geoname_id.
+        networks.insert("198.51.100.0/24".parse().unwrap(), location(3578476, "France"));
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, networks);
+        filter.set_blocked_geoname_ids(vec![3578476]);
+
+        let territory = Ipv4Addr::new(198, 51, 100, 1);
+        assert!(filter.is_ip_blocked(&territory).await);
+        assert!(!filter.is_ip_blocked(&Ipv4Addr::new(203, 0, 113, 1)).await);
+        assert_eq!(
+            filter.decide(territory).await.reason.as_deref(),
+            Some("geoname_id 3578476")
+        );
+
+        let database = filter.database();
+        let networks = database
+            .iter()
+            .map(|(network, country)| (network, country.clone()))
+            .collect();
+        let whitelist = GeoIpv4Filter::from_networks(Mode::WhiteList, networks);
+        whitelist.set_blocked_geoname_ids(vec![3578476]);
+        assert!(!whitelist.is_ip_blocked(&territory).await);
+        assert!(whitelist.is_ip_blocked(&Ipv4Addr::new(203, 0, 113, 1)).await);
+    }
+
+    fn eu_networks() -> DashMap<Ipv4Network, CountryLocation> {
+        let networks = DashMap::new();
+        let france = CountryLocation {
+            is_in_european_union: true,
+            ..location(3017382, "France")
+        };
+        networks.insert("203.0.113.0/24".parse().unwrap(), france);
+        networks.insert("198.51.100.0/24".parse().unwrap(), location(6252001, "United States"));
+        networks
+    }
+
+    #[tokio::test]
+    async fn test_eu_toggles() {
+        let france = Ipv4Addr::new(203, 0, 113, 1);
+        let us = Ipv4Addr::new(198, 51, 100, 1);
+
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        filter.block_eu(true);
+        assert!(filter.is_ip_blocked(&france).await);
+        assert!(!filter.is_ip_blocked(&us).await);
+        assert_eq!(filter.decide(france).await.reason.as_deref(), Some("EU country"));
+
+        filter.block_eu(false);
+        filter.allow_only_eu(true);
+        assert!(!filter.is_ip_blocked(&france).await);
+        assert!(filter.is_ip_blocked(&us).await);
+        // EU countries are still subject to the blacklist.
+        filter.set_countries(vec!["France".to_string()]);
+        assert!(filter.is_ip_blocked(&france).await);
+
+        // In whitelist mode exactly the EU is allowed, even when the list is empty
+        // or names a non-EU country.
+        let whitelist = GeoIpv4Filter::from_networks(Mode::WhiteList, eu_networks());
+        whitelist.set_countries(vec!["United States".to_string()]);
+        whitelist.allow_only_eu(true);
+        assert!(!whitelist.is_ip_blocked(&france).await);
+        assert!(whitelist.is_ip_blocked(&us).await);
+    }
+
+    #[test]
+    fn test_parse_countries() {
+        let contents = "# Blocked countries\nNorway\n\n  Sweden  # since 2024\n#Denmark\n";
+        assert_eq!(parse_countries(contents), ["Norway", "Sweden"]);
+    }
+
+    #[cfg(feature = "notify")]
+    #[test]
+    fn test_watch_countries_file() {
+        let dir = std::env::temp_dir().join(format!(
+            "tower-ipfilter-watch-countries-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("blocked_countries.txt");
+        std::fs::write(&path, "France\n").unwrap();
+
+        let filter = Arc::new(GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks()));
+        let watcher = filter.watch_countries_file(&path).unwrap();
+        assert_eq!(filter.blocked_countries(), ["France"]);
+
+        let replacement = dir.join("blocked_countries.txt.tmp");
+        std::fs::write(&replacement, "# moved on\nUnited States\n").unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while filter.blocked_countries() != ["United States"] {
+            assert!(std::time::Instant::now() < deadline, "countries were not reloaded");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(filter.is_ip_blocked_sync(&Ipv4Addr::new(198, 51, 100, 1)));
+
+        drop(watcher);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_blocked_countries() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        filter.set_countries(vec!["Sweden".to_string(), "Norway".to_string()]);
+        filter.set_blocked_geoname_ids(vec![3017382, 2661886]);
+        assert_eq!(filter.blocked_countries(), ["Norway", "Sweden"]);
+        assert_eq!(filter.blocked_geoname_ids(), [2661886, 3017382]);
+    }
+
+    #[test]
+    fn test_is_blocked_batch() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        filter.set_countries(vec!["France".to_string()]);
+        let ips: Vec<IpAddr> = ["203.0.113.1", "198.51.100.1", "192.0.2.1", "2001:db8::1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+        assert_eq!(filter.is_blocked_batch(&ips), [true, false, false, false]);
+    }
+
+    #[test]
+    fn test_is_ip_blocked_sync() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        filter.set_countries(vec!["France".to_string()]);
+        assert!(filter.is_ip_blocked_sync(&Ipv4Addr::new(203, 0, 113, 1)));
+        assert!(!filter.is_ip_blocked_sync(&Ipv4Addr::new(198, 51, 100, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_ip_follows_mode() {
+        let unknown = Ipv4Addr::new(192, 0, 2, 1);
+
+        let blacklist = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        blacklist.set_countries(vec!["France".to_string()]);
+        assert!(!blacklist.is_ip_blocked(&unknown).await);
+
+        let whitelist = GeoIpv4Filter::from_networks(Mode::WhiteList, eu_networks());
+        whitelist.set_countries(vec!["France".to_string()]);
+        assert!(whitelist.is_ip_blocked(&unknown).await);
+        assert!(!whitelist.is_ip_blocked(&Ipv4Addr::new(203, 0, 113, 1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_networks_use_longest_prefix() {
+        let networks = DashMap::new();
+        networks.insert("10.0.0.0/8".parse().unwrap(), location(1, "United States"));
+        networks.insert("10.1.0.0/16".parse().unwrap(), location(2, "Canada"));
+        networks.insert("10.1.2.0/24".parse().unwrap(), location(3, "Mexico"));
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, networks);
+
+        let country = |ip: [u8; 4]| {
+            let country = filter.country_for_ip(&ip.into());
+            country.and_then(|c| c.country_name.clone())
+        };
+        assert_eq!(country([10, 1, 2, 3]).as_deref(), Some("Mexico"));
+        assert_eq!(country([10, 1, 3, 3]).as_deref(), Some("Canada"));
+        assert_eq!(country([10, 2, 0, 1]).as_deref(), Some("United States"));
+
+        filter.set_countries(vec!["Mexico".to_string()]);
+        assert!(filter.is_ip_blocked(&Ipv4Addr::new(10, 1, 2, 255)).await);
+        assert!(!filter.is_ip_blocked(&Ipv4Addr::new(10, 1, 1, 0)).await);
+    }
+
+    #[tokio::test]
+    async fn test_set_ip_country_overrides_networks() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        filter.set_countries(vec!["Norway".to_string()]);
+        let us = Ipv4Addr::new(198, 51, 100, 1);
+        let unknown = Ipv4Addr::new(192, 0, 2, 1);
+
+        filter.set_ip_country(us, location(2, "Norway"));
+        filter.set_ip_country(unknown, location(2, "Norway"));
+        assert!(filter.is_ip_blocked(&us).await);
+        assert!(filter.is_ip_blocked(&unknown).await);
+        assert!(!filter.is_ip_blocked(&Ipv4Addr::new(198, 51, 100, 2)).await);
+
+        assert!(filter.remove_ip(us));
+        assert!(!filter.remove_ip(us));
+        assert!(!filter.is_ip_blocked(&us).await);
+    }
+
+    #[tokio::test]
+    async fn test_lookups_share_locations() {
+        let networks = eu_networks();
+        networks.insert("192.0.2.0/24".parse().unwrap(), location(6252001, "United States"));
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, networks);
+
+        let first = filter.country_for_ip(&Ipv4Addr::new(198, 51, 100, 1)).unwrap();
+        let again = filter.country_for_ip(&Ipv4Addr::new(198, 51, 100, 2)).unwrap();
+        let other = filter.country_for_ip(&Ipv4Addr::new(192, 0, 2, 1)).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert!(Arc::ptr_eq(&first, &other));
+
+        let decision = filter.decide(Ipv4Addr::new(198, 51, 100, 1)).await;
+        assert!(Arc::ptr_eq(&first, decision.country.as_ref().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_strict_whitelist() {
+        let networks = eu_networks();
+        let no_country = CountryLocation {
+            country_name: None,
+            ..location(0, "")
+        };
+        networks.insert("192.0.2.0/25".parse().unwrap(), no_country);
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, networks);
+        filter.allow_only_eu(true);
+        filter.set_blocked_geoname_ids(vec![6252001]);
+        let filter = filter.strict_whitelist(vec!["France".to_string()]);
+
+        assert_eq!(filter.mode, Mode::WhiteList);
+        assert!(!filter.is_ip_blocked(&Ipv4Addr::new(203, 0, 113, 1)).await);
+        assert!(filter.is_ip_blocked(&Ipv4Addr::new(198, 51, 100, 1)).await);
+        let no_country = filter.decide(Ipv4Addr::new(192, 0, 2, 1)).await;
+        assert!(no_country.blocked);
+        assert_eq!(no_country.reason.as_deref(), Some("unknown country"));
+        assert!(filter.is_ip_blocked(&Ipv4Addr::new(192, 0, 2, 200)).await);
+    }
+
+    #[tokio::test]
+    async fn test_add_reports_unresolved() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        let us = Ipv4Addr::new(198, 51, 100, 1);
+        let unknown = Ipv4Addr::new(192, 0, 2, 1);
+
+        assert!(filter.add_ip(us).await);
+        assert!(filter.addresses.contains_key(&us));
+        assert!(!filter.add_ip(unknown).await);
+        assert!(!filter.addresses.contains_key(&unknown));
+
+        assert!(filter.add_network("198.51.100.128/25".parse().unwrap()).await);
+        assert!(!filter.add_network("192.0.2.0/24".parse().unwrap()).await);
+        assert_eq!(filter.database().len(), 3);
+
+        assert!(filter.remove_network("198.51.100.128/25".parse().unwrap()));
+        assert!(!filter.remove_network("198.51.100.128/25".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_block_unknown_country() {
+        let networks = eu_networks();
+        let no_country = CountryLocation {
+            country_name: None,
+            ..location(0, "")
+        };
+        networks.insert("192.0.2.0/25".parse().unwrap(), no_country);
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, networks);
+        let matched = Ipv4Addr::new(192, 0, 2, 1);
+        let unmatched = Ipv4Addr::new(192, 0, 2, 200);
+
+        // Matched to a location without a country.
+        assert!(!filter.is_ip_blocked(&matched).await);
+        filter.block_unknown_country(true);
+        let decision = filter.decide(matched).await;
+        assert!(decision.blocked);
+        assert_eq!(decision.reason.as_deref(), Some("unknown country"));
+
+        // Not in any network at all.
+        assert!(!filter.is_ip_blocked(&unmatched).await);
+        assert!(!filter.is_ip_blocked(&Ipv4Addr::new(198, 51, 100, 1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_merge_networks_overrides_earlier_sources() {
+        let networks = DashMap::new();
+        networks.insert("203.0.113.0/24".parse().unwrap(), location(1, "France"));
+        networks.insert("203.0.113.128/25".parse().unwrap(), location(1, "France"));
+        networks.insert("198.51.100.0/24".parse().unwrap(), location(1, "France"));
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, networks);
+
+        filter.merge_networks(GeoData {
+            ip_blocks: vec![block("203.0.113.0/24", Some(2)), block("192.0.2.0/24", None)],
+            country_locations: [(2, location(2, "Norway"))].into_iter().collect(),
+        });
+
+        let country = |ip: [u8; 4]| {
+            let filter = filter.clone();
+            async move {
+                let country = filter.country_for_ip(&ip.into());
+                country.and_then(|c| c.country_name.clone())
+            }
+        };
+        assert_eq!(country([203, 0, 113, 200]).await.as_deref(), Some("Norway"));
+        assert_eq!(country([203, 0, 113, 1]).await.as_deref(), Some("Norway"));
+        assert_eq!(country([198, 51, 100, 1]).await.as_deref(), Some("France"));
+        assert_eq!(country([192, 0, 2, 1]).await, None);
+        assert_eq!(filter.database().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_override_network() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        filter.set_countries(vec!["Norway".to_string()]);
+        let ip = Ipv4Addr::new(198, 51, 100, 7);
+        assert!(!filter.is_ip_blocked(&ip).await);
+
+        // Same prefix as the loaded network, the override wins the tie.
+        filter.override_network("198.51.100.0/24".parse().unwrap(), location(2, "Norway"));
+        assert!(filter.is_ip_blocked(&ip).await);
+        assert_eq!(filter.database().len(), 2);
+
+        // A narrower override only covers its own range.
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        filter.set_countries(vec!["Norway".to_string()]);
+        filter.override_network("198.51.100.0/28".parse().unwrap(), location(2, "Norway"));
+        assert!(filter.is_ip_blocked(&ip).await);
+        assert!(!filter.is_ip_blocked(&Ipv4Addr::new(198, 51, 100, 200)).await);
+
+        filter.replace_networks(eu_networks());
+        assert!(filter.is_ip_blocked(&ip).await);
+        assert!(filter.remove_override("198.51.100.0/28".parse().unwrap()));
+        assert!(!filter.is_ip_blocked(&ip).await);
+    }
+
+    #[test]
+    fn test_try_set_countries() {
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks());
+        filter.set_countries(vec!["France".to_string()]);
+
+        let typo = vec!["Untied States".to_string(), "france".to_string()];
+        assert_eq!(filter.try_set_countries(typo), Err(vec!["Untied States".to_string()]));
+        assert_eq!(filter.blocked_countries(), vec!["France"]);
+
+        let countries = vec![" united states".to_string(), "FRANCE".to_string()];
+        assert_eq!(filter.try_set_countries(countries), Ok(()));
+        assert_eq!(filter.blocked_countries(), vec!["France", "United States"]);
+        assert!(filter.is_ip_blocked_sync(&Ipv4Addr::new(198, 51, 100, 1)));
+    }
+
+    #[test]
+    fn test_private_network_policy() {
+        let whitelist = || {
+            let filter = GeoIpv4Filter::from_networks(Mode::WhiteList, eu_networks());
+            filter.set_countries(vec!["France".to_string()]);
+            filter
+        };
+        let cgnat = Ipv4Addr::new(100, 64, 1, 1);
+        let localhost = Ipv4Addr::new(127, 0, 0, 1);
+
+        let filter = whitelist();
+        assert!(filter.is_ip_blocked_sync(&cgnat));
+
+        let filter = whitelist().private_network_policy(PrivateNetworkPolicy::Allow);
+        let decision = filter.decide_ip(&cgnat);
+        assert!(!decision.blocked);
+        assert_eq!(decision.reason.as_deref(), Some("private network"));
+        assert!(!filter.is_ip_blocked_sync(&localhost));
+        assert!(filter.is_ip_blocked_sync(&Ipv4Addr::new(198, 51, 100, 1)));
+
+        let filter = GeoIpv4Filter::from_networks(Mode::BlackList, eu_networks())
+            .private_network_policy(PrivateNetworkPolicy::Deny)
+            .private_networks(vec!["203.0.113.0/25".parse().unwrap()]);
+        assert!(filter.is_ip_blocked_sync(&Ipv4Addr::new(203, 0, 113, 1)));
+        assert!(!filter.is_ip_blocked_sync(&Ipv4Addr::new(203, 0, 113, 200)));
+        assert!(!filter.is_ip_blocked_sync(&cgnat));
+    }
+
+    #[tokio::test]
+    async fn test_geo_database() {
+        let networks = eu_networks();
+        networks.insert("203.0.113.128/25".parse().unwrap(), location(2, "Norway"));
+        let database = GeoDatabase::from_networks(networks);
+        let country = |ip: &str| {
+            let country = database.lookup(ip.parse().unwrap());
+            country.and_then(|country| country.country_name.as_deref())
+        };
+
+        assert_eq!(database.len(), 3);
+        assert_eq!(country("203.0.113.1"), Some("France"));
+        assert_eq!(country("203.0.113.200"), Some("Norway"));
+        assert_eq!(country("192.0.2.1"), None);
+        assert_eq!(country("2001:db8::1"), None);
+
+        // The filter reads the same database and its changes leave snapshots untouched.
+        let filter = GeoIpv4Filter::from_database(Mode::BlackList, database.clone());
+        let snapshot = filter.database();
+        assert!(filter.remove_network("203.0.113.128/25".parse().unwrap()));
+        assert!(!filter.remove_network("203.0.113.128/25".parse().unwrap()));
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(filter.database().len(), 2);
+        let ip = Ipv4Addr::new(203, 0, 113, 200);
+        let resolved = filter.country_for_ip(&ip).unwrap();
+        assert_eq!(resolved.country_name.as_deref(), Some("France"));
+    }
 }
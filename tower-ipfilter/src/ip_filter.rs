@@ -1,19 +1,267 @@
-use std::{marker::PhantomData, net::IpAddr};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    marker::PhantomData,
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use dashmap::DashMap;
-use ipnetwork::IpNetwork;
+use arc_swap::ArcSwap;
+use dashmap::{mapref::entry::Entry, DashMap};
+use ipnetwork::{IpNetwork, IpNetworkError, Ipv4Network, Ipv6Network};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 use crate::{
     body::{create_ip_address_denied_response, IpResponseBody},
+    error::DateParseError,
     geo_filter::IpAddrExt,
-    network_filter_service::NetworkFilter,
+    network_filter_service::{FilterDecision, NetworkFilter},
     types::Mode,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IpMetaData {
     pub reason: String,
-    pub date: String,
+    /// When the entry was added.
+    pub date: BlockDate,
+    /// When the entry stops applying; `None` for a permanent entry.
+    pub expires: Option<SystemTime>,
+}
+
+impl IpMetaData {
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= SystemTime::now())
+    }
+}
+
+const SECS_PER_DAY: i64 = 86_400;
+
+/// A point in time, such as when an entry was added to an [`IpFilter`].
+///
+/// Parsed from and displayed as a UTC calendar date, `2024-01-10`, or a UTC
+/// timestamp, `2024-01-10T08:30:00Z`, so dates are validated when entered and
+/// compare chronologically. With the `chrono` feature it converts to and from
+/// `chrono::DateTime<Utc>`, and chrono does the calendar arithmetic.
+///
+/// Dates are kept to the whole second, truncating any fraction, so a date
+/// written out and parsed back compares equal to the original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockDate(SystemTime);
+
+impl BlockDate {
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    /// Midnight UTC of the given day, or `None` if the day does not exist.
+    pub fn from_ymd(year: i32, month: u32, day: u32) -> Option<Self> {
+        days_from_civil(year, month, day).map(|days| Self::from_unix_secs(days * SECS_PER_DAY))
+    }
+
+    pub fn as_system_time(&self) -> SystemTime {
+        self.0
+    }
+
+    fn from_unix_secs(secs: i64) -> Self {
+        let offset = Duration::from_secs(secs.unsigned_abs());
+        if secs >= 0 {
+            Self(UNIX_EPOCH + offset)
+        } else {
+            Self(UNIX_EPOCH - offset)
+        }
+    }
+
+    /// Whole seconds since the epoch, rounded down.
+    fn unix_secs(&self) -> i64 {
+        match self.0.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(err) => {
+                let before = err.duration();
+                -(before.as_secs() as i64) - i64::from(before.subsec_nanos() > 0)
+            }
+        }
+    }
+}
+
+impl From<SystemTime> for BlockDate {
+    fn from(time: SystemTime) -> Self {
+        Self::from_unix_secs(Self(time).unix_secs())
+    }
+}
+
+impl From<BlockDate> for SystemTime {
+    fn from(date: BlockDate) -> Self {
+        date.0
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<chrono::DateTime<chrono::Utc>> for BlockDate {
+    fn from(time: chrono::DateTime<chrono::Utc>) -> Self {
+        SystemTime::from(time).into()
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl From<BlockDate> for chrono::DateTime<chrono::Utc> {
+    fn from(date: BlockDate) -> Self {
+        date.0.into()
+    }
+}
+
+impl fmt::Display for BlockDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.unix_secs();
+        let (year, month, day) = civil_from_days(secs.div_euclid(SECS_PER_DAY));
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)?;
+        let time = secs.rem_euclid(SECS_PER_DAY);
+        if time != 0 {
+            write!(
+                f,
+                "T{:02}:{:02}:{:02}Z",
+                time / 3600,
+                time / 60 % 60,
+                time % 60
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BlockDate {
+    type Err = DateParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || DateParseError(s.to_string());
+        let number = |part: &str| {
+            if !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()) {
+                part.parse::<u32>().map_err(|_| err())
+            } else {
+                Err(err())
+            }
+        };
+
+        let (date, time) = match s.split_once('T') {
+            Some((date, time)) => (date, Some(time.strip_suffix('Z').ok_or_else(err)?)),
+            None => (s, None),
+        };
+        let [year, month, day] = split_exact(date, '-').ok_or_else(err)?;
+        if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return Err(err());
+        }
+        let date =
+            Self::from_ymd(number(year)? as i32, number(month)?, number(day)?).ok_or_else(err)?;
+
+        let Some(time) = time else {
+            return Ok(date);
+        };
+        let [hour, minute, second] = split_exact(time, ':').ok_or_else(err)?;
+        if hour.len() != 2 || minute.len() != 2 || second.len() != 2 {
+            return Err(err());
+        }
+        let (hour, minute, second) = (number(hour)?, number(minute)?, number(second)?);
+        if hour > 23 || minute > 59 || second > 59 {
+            return Err(err());
+        }
+        let secs = i64::from(hour * 3600 + minute * 60 + second);
+        Ok(Self::from_unix_secs(date.unix_secs() + secs))
+    }
+}
+
+impl Serialize for BlockDate {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockDate {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn split_exact(s: &str, separator: char) -> Option<[&str; 3]> {
+    let mut parts = s.split(separator);
+    let parts = [parts.next()?, parts.next()?, parts.next()?];
+    (s.matches(separator).count() == 2).then_some(parts)
+}
+
+#[cfg(not(feature = "chrono"))]
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, or `None` if the day
+/// does not exist.
+#[cfg(feature = "chrono")]
+fn days_from_civil(year: i32, month: u32, day: u32) -> Option<i64> {
+    let date = chrono::NaiveDate::from_ymd_opt(year, month, day)?;
+    Some(date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp() / SECS_PER_DAY)
+}
+
+/// The inverse of [`days_from_civil`]. Days beyond the roughly 262,000 years
+/// chrono supports are clamped to its first or last date.
+#[cfg(feature = "chrono")]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    use chrono::{Datelike, NaiveDate, TimeDelta};
+
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).expect("valid date");
+    let date = TimeDelta::try_days(days)
+        .and_then(|days| epoch.checked_add_signed(days))
+        .unwrap_or(if days < 0 {
+            NaiveDate::MIN
+        } else {
+            NaiveDate::MAX
+        });
+    (date.year().into(), date.month(), date.day())
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, or `None` if the day
+/// does not exist.
+#[cfg(not(feature = "chrono"))]
+fn days_from_civil(year: i32, month: u32, day: u32) -> Option<i64> {
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+    let year = i64::from(year) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = i64::from(month);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+/// The inverse of [`days_from_civil`].
+#[cfg(not(feature = "chrono"))]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
 }
 
 #[derive(Debug, Clone)]
@@ -22,208 +270,1528 @@ pub enum V4 {}
 #[derive(Debug, Clone)]
 pub enum V6 {}
 
-pub trait IpType {}
+/// Marker for a filter that accepts both IPv4 and IPv6 addresses.
+#[derive(Debug, Clone)]
+pub enum Dual {}
+
+pub trait IpType {
+    /// Whether filters of this type list addresses of `ip`'s family. Other
+    /// addresses are never listed, so they are decided like unlisted ones.
+    fn handles(_ip: &IpAddr) -> bool {
+        true
+    }
+}
+
+impl IpType for V4 {
+    fn handles(ip: &IpAddr) -> bool {
+        ip.is_ipv4()
+    }
+}
+
+impl IpType for V6 {
+    fn handles(ip: &IpAddr) -> bool {
+        ip.is_ipv6()
+    }
+}
+
+impl IpType for Dual {}
 
-impl IpType for V4 {}
-impl IpType for V6 {}
+/// An [`IpFilter`] covering both address families, for dual-stack listeners.
+pub type DualIpFilter = IpFilter<Dual>;
+
+/// Serializable copy of an [`IpFilter`]'s entries, see [`IpFilter::export`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilterSnapshot {
+    pub mode: Mode,
+    pub addresses: Vec<(IpAddr, IpMetaData)>,
+    pub networks: Vec<(IpNetwork, IpMetaData)>,
+}
 
 #[derive(Debug, Clone)]
 pub struct IpFilter<S: IpType> {
     pub addresses: DashMap<IpAddr, IpMetaData>,
-    pub networks: DashMap<IpNetwork, IpMetaData>,
+    /// Keyed by network address, e.g. `203.0.113.0/24`, and only changed
+    /// together with `index`.
+    networks: DashMap<IpNetwork, IpMetaData>,
+    index: NetworkIndex,
     pub mode: Mode,
+    /// Shared by clones, see [`IpFilter::enable_auto_ban`].
+    auto_ban: Option<Arc<RateTracker>>,
     marker: PhantomData<S>,
 }
 
+/// Counts requests per address in fixed windows, for [`IpFilter::enable_auto_ban`].
+#[derive(Debug)]
+struct RateTracker {
+    max_requests: u32,
+    window: Duration,
+    ban_duration: Duration,
+    /// Start of the current window and the requests counted in it.
+    counts: DashMap<IpAddr, (Instant, u32)>,
+    recorded: AtomicUsize,
+}
+
+impl RateTracker {
+    /// Stale windows are dropped every this many requests.
+    const PRUNE_INTERVAL: usize = 1024;
+
+    /// Counts a request from `ip`, returning `true` once it goes over the limit.
+    fn record(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let exceeded = {
+            let mut entry = self.counts.entry(ip).or_insert((now, 0));
+            let (started, count) = entry.value_mut();
+            if now.duration_since(*started) >= self.window {
+                *started = now;
+                *count = 0;
+            }
+            *count += 1;
+            *count > self.max_requests
+        };
+        if exceeded {
+            self.counts.remove(&ip);
+        }
+        let recorded = self.recorded.fetch_add(1, Ordering::Relaxed);
+        if recorded.is_multiple_of(Self::PRUNE_INTERVAL) {
+            self.counts
+                .retain(|_, (started, _)| now.duration_since(*started) < self.window);
+        }
+        exceeded
+    }
+}
+
+/// Longest-prefix index over the networks of an [`IpFilter`].
+///
+/// Writers hold the lock while changing both the index and the networks, so
+/// the two never disagree.
+#[derive(Debug, Default)]
+struct NetworkIndex(RwLock<CidrSet>);
+
+impl NetworkIndex {
+    fn read(&self) -> RwLockReadGuard<'_, CidrSet> {
+        self.0.read().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, CidrSet> {
+        self.0.write().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Clone for NetworkIndex {
+    fn clone(&self) -> Self {
+        Self(RwLock::new(self.read().clone()))
+    }
+}
+
 impl<S: IpType> IpFilter<S> {
     pub fn new(mode: Mode) -> Self {
         Self {
             networks: DashMap::new(),
+            index: NetworkIndex::default(),
             addresses: DashMap::new(),
             mode,
+            auto_ban: None,
             marker: PhantomData,
         }
     }
-    pub async fn add_ip(&self, ip: IpAddr, reason: String, date: String) {
-        self.addresses.insert(ip, IpMetaData { reason, date });
+    pub async fn add_ip(&self, ip: IpAddr, reason: String, date: BlockDate) {
+        self.addresses.insert(
+            ip,
+            IpMetaData {
+                reason,
+                date,
+                expires: None,
+            },
+        );
+    }
+    /// Adds `network`, stored by its network address, so `203.0.113.5/24` is
+    /// listed as `203.0.113.0/24`.
+    pub async fn add_network(&self, network: IpNetwork, reason: String, date: BlockDate) {
+        self.insert_network(
+            network,
+            IpMetaData {
+                reason,
+                date,
+                expires: None,
+            },
+        );
+    }
+
+    /// Adds many addresses at once, e.g. when loading a large blocklist.
+    pub fn add_ips(&self, entries: impl IntoIterator<Item = (IpAddr, IpMetaData)>) {
+        for (ip, meta) in entries {
+            self.addresses.insert(ip, meta);
+        }
+    }
+
+    /// Adds many networks at once, see [`IpFilter::add_ips`].
+    pub fn add_networks(&self, entries: impl IntoIterator<Item = (IpNetwork, IpMetaData)>) {
+        for (network, meta) in entries {
+            self.insert_network(network, meta);
+        }
+    }
+
+    /// Adds `ip` for a limited time, e.g. a 24 hour ban.
+    ///
+    /// Once `duration` has passed the entry is ignored and removed on a later lookup.
+    pub async fn add_ip_for(
+        &self,
+        ip: IpAddr,
+        reason: String,
+        date: BlockDate,
+        duration: Duration,
+    ) {
+        self.addresses.insert(
+            ip,
+            IpMetaData {
+                reason,
+                date,
+                expires: Some(SystemTime::now() + duration),
+            },
+        );
+    }
+
+    /// Adds `network` for a limited time, see [`IpFilter::add_ip_for`].
+    pub async fn add_network_for(
+        &self,
+        network: IpNetwork,
+        reason: String,
+        date: BlockDate,
+        duration: Duration,
+    ) {
+        self.insert_network(
+            network,
+            IpMetaData {
+                reason,
+                date,
+                expires: Some(SystemTime::now() + duration),
+            },
+        );
+    }
+
+    /// Blocks an address for `ban_duration` once it sends more than
+    /// `max_requests` requests within `window`.
+    ///
+    /// Requests are counted by [`Filter`](crate::network_filter_service::Filter)
+    /// through [`NetworkFilter::record_request`], in fixed windows per address,
+    /// and a ban is an [`IpFilter::add_ip_for`] entry. This only escalates to a
+    /// block, it doesn't limit the rate below the threshold, and never shortens
+    /// an existing block. Bans only make sense in blacklist mode, in whitelist
+    /// mode requests aren't counted.
+    ///
+    /// Set this up before sharing the filter; its clones count requests together.
+    pub fn enable_auto_ban(
+        mut self,
+        max_requests: u32,
+        window: Duration,
+        ban_duration: Duration,
+    ) -> Self {
+        self.auto_ban = Some(Arc::new(RateTracker {
+            max_requests,
+            window,
+            ban_duration,
+            counts: DashMap::new(),
+            recorded: AtomicUsize::new(0),
+        }));
+        self
+    }
+
+    fn count_request(&self, ip: IpAddr) {
+        let Some(tracker) = &self.auto_ban else {
+            return;
+        };
+        if self.mode != Mode::BlackList || !tracker.record(ip) {
+            return;
+        }
+        let expires = SystemTime::now() + tracker.ban_duration;
+        let ban = IpMetaData {
+            reason: format!(
+                "auto-ban: over {} requests in {:?}",
+                tracker.max_requests, tracker.window
+            ),
+            date: BlockDate::now(),
+            expires: Some(expires),
+        };
+        match self.addresses.entry(ip) {
+            // A longer or permanent block stays as it is.
+            Entry::Occupied(entry)
+                if entry.get().expires.is_none_or(|current| current >= expires) => {}
+            entry => {
+                warn!("Auto-banning {} for {:?}", ip, tracker.ban_duration);
+                entry.insert(ban);
+            }
+        }
+    }
+
+    /// Copies the current entries, e.g. to persist the filter across restarts.
+    ///
+    /// Expired entries are left out.
+    pub fn export(&self) -> FilterSnapshot {
+        FilterSnapshot {
+            mode: self.mode.clone(),
+            addresses: self
+                .addresses
+                .iter()
+                .filter(|kv| !kv.value().is_expired())
+                .map(|kv| (*kv.key(), kv.value().clone()))
+                .collect(),
+            networks: self.networks(),
+        }
+    }
+
+    /// Copies the listed networks with their metadata, leaving out expired
+    /// entries. Addresses are in the public `addresses` map.
+    ///
+    /// Add and remove networks with [`IpFilter::add_network`] and
+    /// [`IpFilter::remove_network`], which keep the lookup index in step.
+    pub fn networks(&self) -> Vec<(IpNetwork, IpMetaData)> {
+        self.networks
+            .iter()
+            .filter(|kv| !kv.value().is_expired())
+            .map(|kv| (*kv.key(), kv.value().clone()))
+            .collect()
+    }
+
+    /// Returns the number of listed (addresses, networks), leaving out
+    /// expired entries.
+    pub fn blocked_count(&self) -> (usize, usize) {
+        let live = |meta: &IpMetaData| !meta.is_expired();
+        (
+            self.addresses.iter().filter(|kv| live(kv.value())).count(),
+            self.networks.iter().filter(|kv| live(kv.value())).count(),
+        )
+    }
+
+    /// Iterates over a copy of the listed entries, addresses first as
+    /// single-host networks. Expired entries are left out.
+    ///
+    /// The copy is taken up front, so the filter can be changed while iterating.
+    pub fn iter_blocked(&self) -> impl Iterator<Item = (IpNetwork, IpMetaData)> {
+        let FilterSnapshot {
+            addresses,
+            networks,
+            ..
+        } = self.export();
+        addresses
+            .into_iter()
+            .map(|(ip, meta)| (IpNetwork::from(ip), meta))
+            .chain(networks)
+    }
+
+    /// Rebuilds a filter from a snapshot taken with [`IpFilter::export`].
+    pub fn import(snapshot: FilterSnapshot) -> Self {
+        let filter = Self::new(snapshot.mode);
+        filter.add_ips(snapshot.addresses);
+        filter.add_networks(snapshot.networks);
+        filter
+    }
+
+    fn insert_network(&self, network: IpNetwork, meta: IpMetaData) {
+        let network = normalize(network);
+        let mut index = self.index.write();
+        index.insert(network);
+        self.networks.insert(network, meta);
+    }
+
+    /// Removes the entry for `ip`, returning whether it was listed.
+    ///
+    /// An expired entry counts as not listed. Removing never waits, so this
+    /// isn't async; [`NetworkFilter::unblock`] is the async equivalent.
+    pub fn remove_ip(&self, ip: IpAddr) -> bool {
+        self.addresses
+            .remove(&ip)
+            .is_some_and(|(_, meta)| !meta.is_expired())
     }
-    pub async fn add_network(&self, network: IpNetwork, reason: String, date: String) {
-        self.networks.insert(network, IpMetaData { reason, date });
+
+    /// Removes `network`, returning whether it was listed, see [`IpFilter::remove_ip`].
+    pub fn remove_network(&self, network: IpNetwork) -> bool {
+        let network = normalize(network);
+        let mut index = self.index.write();
+        index.remove(network);
+        self.networks
+            .remove(&network)
+            .is_some_and(|(_, meta)| !meta.is_expired())
+    }
+
+    /// Parses `ip` (e.g. `"203.0.113.5"`) and adds it with [`IpFilter::add_ip`].
+    pub async fn add_ip_str(
+        &self,
+        ip: &str,
+        reason: String,
+        date: BlockDate,
+    ) -> Result<(), AddrParseError> {
+        let ip = ip.trim().parse()?;
+        self.add_ip(ip, reason, date).await;
+        Ok(())
+    }
+
+    /// Parses `cidr` (e.g. `"203.0.113.0/24"`) and adds it with [`IpFilter::add_network`].
+    ///
+    /// A bare address is accepted and treated as a single-host network.
+    pub async fn add_network_str(
+        &self,
+        cidr: &str,
+        reason: String,
+        date: BlockDate,
+    ) -> Result<(), IpNetworkError> {
+        let network = cidr.trim().parse()?;
+        self.add_network(network, reason, date).await;
+        Ok(())
+    }
+
+    /// Adds one network per line, as found in a plain-text blocklist.
+    ///
+    /// Blank lines and lines starting with `#` are skipped. Returns the number
+    /// of networks added together with the lines that failed to parse.
+    pub async fn add_networks_from_lines(
+        &self,
+        lines: impl Iterator<Item = String>,
+        reason: &str,
+        date: BlockDate,
+    ) -> (usize, Vec<(String, IpNetworkError)>) {
+        let mut added = 0;
+        let mut errors = Vec::new();
+        for line in lines {
+            let entry = line.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            match self.add_network_str(entry, reason.to_string(), date).await {
+                Ok(()) => added += 1,
+                Err(err) => errors.push((line, err)),
+            }
+        }
+        (added, errors)
     }
 
     async fn is_ip_blocked(&self, ip: &IpAddr) -> bool {
-        if self.addresses.contains_key(ip) {
-            match self.mode {
-                Mode::BlackList => return true,
-                Mode::WhiteList => return false,
+        self.is_ip_blocked_sync(ip)
+    }
+
+    /// Whether `ip` is blocked, for callers outside an async context.
+    ///
+    /// Lookups never wait on I/O, so this doesn't block. Addresses of a family
+    /// the filter doesn't handle count as unlisted, see [`IpType::handles`].
+    pub fn is_ip_blocked_sync(&self, ip: &IpAddr) -> bool {
+        self.decide_ip(ip).blocked
+    }
+
+    /// Decides on `ip`, addresses of another family count as unlisted.
+    fn decide_ip(&self, ip: &IpAddr) -> FilterDecision {
+        if !S::handles(ip) {
+            return FilterDecision::new(self.is_unlisted_blocked());
+        }
+        match self.lookup(ip) {
+            Some(meta) => FilterDecision::new(self.is_listed_blocked())
+                .with_reason(meta.reason)
+                .with_expiry(meta.expires),
+            None => FilterDecision::new(self.is_unlisted_blocked()),
+        }
+    }
+
+    /// Returns the metadata of the entry matching `ip`, either the address
+    /// itself or the most specific network containing it, or `None` if `ip`
+    /// is not listed.
+    ///
+    /// Expired entries seen along the way are evicted.
+    pub fn lookup(&self, ip: &IpAddr) -> Option<IpMetaData> {
+        match self.addresses.get(ip).map(|meta| meta.clone()) {
+            Some(meta) if !meta.is_expired() => return Some(meta),
+            Some(_) => {
+                self.addresses.remove_if(ip, |_, meta| meta.is_expired());
             }
-        } else {
-            for kv in self.networks.iter() {
-                let (network, _) = kv.pair();
-                if network.contains(*ip) {
-                    match self.mode {
-                        Mode::BlackList => return true,
-                        Mode::WhiteList => return false,
-                    }
+            None => {}
+        }
+
+        let mut expired = Vec::new();
+        let mut matched = None;
+        for network in self.index.read().matches(*ip) {
+            match self.networks.get(&network) {
+                Some(meta) if meta.is_expired() => expired.push(network),
+                Some(meta) => {
+                    matched = Some(meta.clone());
+                    break;
                 }
+                None => {}
             }
-
-            match self.mode {
-                Mode::BlackList => return false,
-                Mode::WhiteList => return true,
+        }
+        // Evict after the lookup, the index can't be written while it's being read.
+        for network in expired {
+            let mut index = self.index.write();
+            if self
+                .networks
+                .remove_if(&network, |_, meta| meta.is_expired())
+                .is_some()
+            {
+                index.remove(network);
             }
         }
+
+        matched
+    }
+
+    /// Whether an address that matches an entry in the filter is blocked.
+    fn is_listed_blocked(&self) -> bool {
+        match self.mode {
+            Mode::BlackList => true,
+            Mode::WhiteList => false,
+        }
+    }
+
+    /// Checks `ips` under one read of the network index, without evicting
+    /// expired entries. Addresses of another family count as unlisted.
+    fn blocked_batch(&self, ips: &[IpAddr]) -> Vec<bool> {
+        let index = self.index.read();
+        let listed_network = |network: IpNetwork| {
+            self.networks
+                .get(&network)
+                .is_some_and(|meta| !meta.is_expired())
+        };
+        ips.iter()
+            .map(|ip| {
+                let listed = S::handles(ip)
+                    && (self
+                        .addresses
+                        .get(ip)
+                        .is_some_and(|meta| !meta.is_expired())
+                        || index.matches(*ip).any(listed_network));
+                if listed {
+                    self.is_listed_blocked()
+                } else {
+                    self.is_unlisted_blocked()
+                }
+            })
+            .collect()
+    }
+
+    /// Whether an address that matches no entry in the filter is blocked.
+    ///
+    /// This is also the answer given for addresses of the wrong IP version.
+    fn is_unlisted_blocked(&self) -> bool {
+        !self.is_listed_blocked()
     }
 
     async fn block_ip(&self, ip: impl IpAddrExt, network: bool) {
+        let reason = "Blocked".to_string();
         if network {
-            match ip.to_network() {
-                IpNetwork::V4(ip) => {
-                    self.add_network(
-                        IpNetwork::V4(ip),
-                        "Blocked".to_string(),
-                        "2021-09-01".to_string(),
-                    )
-                    .await;
-                }
-                IpNetwork::V6(ip) => {
-                    self.add_network(
-                        IpNetwork::V6(ip),
-                        "Blocked".to_string(),
-                        "2021-09-01".to_string(),
-                    )
-                    .await;
-                }
-            }
+            self.add_network(ip.to_network(), reason, BlockDate::now())
+                .await;
         } else {
-            match ip.to_ip_addr() {
-                IpAddr::V4(ip) => {
-                    self.add_ip(
-                        IpAddr::V4(ip),
-                        "Blocked".to_string(),
-                        "2021-09-01".to_string(),
-                    )
-                    .await;
-                }
-                IpAddr::V6(ip) => {
-                    self.add_ip(
-                        IpAddr::V6(ip),
-                        "Blocked".to_string(),
-                        "2021-09-01".to_string(),
-                    )
-                    .await;
-                }
-            }
+            self.add_ip(ip.to_ip_addr(), reason, BlockDate::now()).await;
         }
     }
 
     async fn unblock_ip(&self, ip: impl IpAddrExt, network: bool) {
         if network {
-            match ip.to_network() {
-                IpNetwork::V4(ip) => {
-                    self.networks.remove(&IpNetwork::V4(ip));
-                }
-                IpNetwork::V6(ip) => {
-                    self.networks.remove(&IpNetwork::V6(ip));
-                }
-            }
+            self.remove_network(ip.to_network());
         } else {
-            match ip.to_ip_addr() {
-                IpAddr::V4(ip) => {
-                    self.addresses.remove(&IpAddr::V4(ip));
-                }
-                IpAddr::V6(ip) => {
-                    self.addresses.remove(&IpAddr::V6(ip));
-                }
-            }
+            self.remove_ip(ip.to_ip_addr());
         }
     }
 }
 
 impl NetworkFilter for IpFilter<V4> {
-    fn block(
-        &self,
-        ip: impl IpAddrExt,
-        network: bool,
-    ) -> impl std::future::Future<Output = ()> + Send {
-        async move {
-            if ip.is_ipv4() {
-                self.block_ip(ip, network).await;
-            } else {
-                panic!("Invalid IP address");
-            }
+    async fn block(&self, ip: impl IpAddrExt, network: bool) {
+        if ip.is_ipv4() {
+            self.block_ip(ip, network).await;
+        } else {
+            warn!(
+                "Ignoring block of IPv6 address {} in IPv4 filter",
+                ip.to_ip_addr()
+            );
         }
     }
 
-    fn unblock(
-        &self,
-        ip: impl IpAddrExt,
-        network: bool,
-    ) -> impl std::future::Future<Output = ()> + Send {
-        async move {
-            if ip.is_ipv4() {
-                self.unblock_ip(ip, network).await;
-            } else {
-                panic!("Invalid IP address");
-            }
+    async fn unblock(&self, ip: impl IpAddrExt, network: bool) {
+        if ip.is_ipv4() {
+            self.unblock_ip(ip, network).await;
+        } else {
+            warn!(
+                "Ignoring unblock of IPv6 address {} in IPv4 filter",
+                ip.to_ip_addr()
+            );
         }
     }
 
-    fn is_blocked(&self, ip: impl IpAddrExt) -> impl std::future::Future<Output = bool> + Send {
-        async move {
-            if ip.is_ipv4() {
-                self.is_ip_blocked(&ip.to_ip_addr()).await
-            } else {
-                panic!("Invalid IP address");
-            }
+    async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+        self.is_ip_blocked(&ip.to_ip_addr()).await
+    }
+
+    async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+        self.decide_ip(&ip.to_ip_addr())
+    }
+
+    fn decide_now(&self, ip: IpAddr) -> Option<FilterDecision> {
+        Some(self.decide_ip(&ip))
+    }
+
+    fn is_blocked_batch(&self, ips: &[IpAddr]) -> Vec<bool> {
+        self.blocked_batch(ips)
+    }
+
+    fn record_request(&self, ip: IpAddr) {
+        if ip.is_ipv4() {
+            self.count_request(ip);
         }
     }
 
     fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
         create_ip_address_denied_response()
     }
+
+    fn mode(&self) -> Option<Mode> {
+        Some(self.mode.clone())
+    }
 }
 
 impl NetworkFilter for IpFilter<V6> {
-    fn block(
-        &self,
-        ip: impl IpAddrExt,
-        network: bool,
-    ) -> impl std::future::Future<Output = ()> + Send {
-        async move {
-            if !ip.is_ipv4() {
-                self.block_ip(ip, network).await;
-            } else {
-                panic!("Invalid IP address");
+    async fn block(&self, ip: impl IpAddrExt, network: bool) {
+        if !ip.is_ipv4() {
+            self.block_ip(ip, network).await;
+        } else {
+            warn!(
+                "Ignoring block of IPv4 address {} in IPv6 filter",
+                ip.to_ip_addr()
+            );
+        }
+    }
+
+    async fn unblock(&self, ip: impl IpAddrExt, network: bool) {
+        if !ip.is_ipv4() {
+            self.unblock_ip(ip, network).await;
+        } else {
+            warn!(
+                "Ignoring unblock of IPv4 address {} in IPv6 filter",
+                ip.to_ip_addr()
+            );
+        }
+    }
+
+    async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+        self.is_ip_blocked(&ip.to_ip_addr()).await
+    }
+
+    async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+        self.decide_ip(&ip.to_ip_addr())
+    }
+
+    fn decide_now(&self, ip: IpAddr) -> Option<FilterDecision> {
+        Some(self.decide_ip(&ip))
+    }
+
+    fn is_blocked_batch(&self, ips: &[IpAddr]) -> Vec<bool> {
+        self.blocked_batch(ips)
+    }
+
+    fn record_request(&self, ip: IpAddr) {
+        if ip.is_ipv6() {
+            self.count_request(ip);
+        }
+    }
+
+    fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
+        create_ip_address_denied_response()
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        Some(self.mode.clone())
+    }
+}
+
+impl NetworkFilter for IpFilter<Dual> {
+    async fn block(&self, ip: impl IpAddrExt, network: bool) {
+        self.block_ip(ip, network).await;
+    }
+
+    async fn unblock(&self, ip: impl IpAddrExt, network: bool) {
+        self.unblock_ip(ip, network).await;
+    }
+
+    async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+        self.is_ip_blocked(&ip.to_ip_addr()).await
+    }
+
+    async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+        self.decide_ip(&ip.to_ip_addr())
+    }
+
+    fn decide_now(&self, ip: IpAddr) -> Option<FilterDecision> {
+        Some(self.decide_ip(&ip))
+    }
+
+    fn is_blocked_batch(&self, ips: &[IpAddr]) -> Vec<bool> {
+        self.blocked_batch(ips)
+    }
+
+    fn record_request(&self, ip: IpAddr) {
+        self.count_request(ip);
+    }
+
+    fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
+        create_ip_address_denied_response()
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        Some(self.mode.clone())
+    }
+}
+
+/// A set of networks answering longest-prefix queries.
+///
+/// Networks are grouped by prefix length, so a lookup costs one hash probe per
+/// distinct prefix length instead of a scan over every network.
+#[derive(Debug, Clone, Default)]
+struct CidrSet {
+    /// Network addresses by prefix length, longest prefix first.
+    v4: Vec<(u8, HashSet<u32>)>,
+    v6: Vec<(u8, HashSet<u128>)>,
+}
+
+impl CidrSet {
+    fn insert(&mut self, network: IpNetwork) {
+        match network {
+            IpNetwork::V4(network) => {
+                prefix_group(&mut self.v4, network.prefix()).insert(network.network().into());
+            }
+            IpNetwork::V6(network) => {
+                prefix_group(&mut self.v6, network.prefix()).insert(network.network().into());
             }
         }
     }
 
-    fn unblock(
-        &self,
-        ip: impl IpAddrExt,
-        network: bool,
-    ) -> impl std::future::Future<Output = ()> + Send {
-        async move {
-            if !ip.is_ipv4() {
-                self.unblock_ip(ip, network).await;
-            } else {
-                panic!("Invalid IP address");
+    fn remove(&mut self, network: IpNetwork) -> bool {
+        match network {
+            IpNetwork::V4(network) => {
+                prefix_group(&mut self.v4, network.prefix()).remove(&network.network().into())
+            }
+            IpNetwork::V6(network) => {
+                prefix_group(&mut self.v6, network.prefix()).remove(&network.network().into())
             }
         }
     }
 
-    fn is_blocked(&self, ip: impl IpAddrExt) -> impl std::future::Future<Output = bool> + Send {
-        async move {
-            if !ip.is_ipv4() {
-                self.is_ip_blocked(&ip.to_ip_addr()).await
-            } else {
-                panic!("Invalid IP address");
+    fn longest_match(&self, ip: IpAddr) -> Option<IpNetwork> {
+        self.matches(ip).next()
+    }
+
+    /// Every network containing `ip`, longest prefix first.
+    fn matches(&self, ip: IpAddr) -> impl Iterator<Item = IpNetwork> + '_ {
+        let (v4, v6) = match ip {
+            IpAddr::V4(ip) => (Some(u32::from(ip)), None),
+            IpAddr::V6(ip) => (None, Some(u128::from(ip))),
+        };
+        let v4 = v4.into_iter().flat_map(move |ip| {
+            self.v4.iter().filter_map(move |(prefix, networks)| {
+                let network = ip & u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                networks.contains(&network).then(|| {
+                    IpNetwork::V4(Ipv4Network::new(Ipv4Addr::from(network), *prefix).unwrap())
+                })
+            })
+        });
+        let v6 = v6.into_iter().flat_map(move |ip| {
+            self.v6.iter().filter_map(move |(prefix, networks)| {
+                let network = ip & u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                networks.contains(&network).then(|| {
+                    IpNetwork::V6(Ipv6Network::new(Ipv6Addr::from(network), *prefix).unwrap())
+                })
+            })
+        });
+        v4.chain(v6)
+    }
+
+    fn len(&self) -> usize {
+        let v4: usize = self.v4.iter().map(|(_, networks)| networks.len()).sum();
+        let v6: usize = self.v6.iter().map(|(_, networks)| networks.len()).sum();
+        v4 + v6
+    }
+}
+
+/// Returns the group for `prefix`, keeping the groups ordered longest prefix first.
+pub(crate) fn prefix_group<C: Default>(groups: &mut Vec<(u8, C)>, prefix: u8) -> &mut C {
+    let index = match groups.binary_search_by(|(existing, _)| prefix.cmp(existing)) {
+        Ok(index) => index,
+        Err(index) => {
+            groups.insert(index, (prefix, C::default()));
+            index
+        }
+    };
+    &mut groups[index].1
+}
+
+/// A filter over a plain list of networks, e.g. a `blocklist.txt` from a
+/// threat-intel feed, without any GeoIP data.
+///
+/// The list holds one address or CIDR per line; blank lines and lines starting
+/// with `#` are skipped. Cloning is cheap: clones share the loaded networks,
+/// including replacements made with [`CidrSetFilter::reload_from_path`].
+#[derive(Debug, Clone)]
+pub struct CidrSetFilter {
+    networks: Arc<ArcSwap<CidrSet>>,
+    pub mode: Mode,
+}
+
+impl CidrSetFilter {
+    pub fn from_path(path: impl AsRef<Path>, mode: Mode) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?), mode)
+    }
+
+    /// Reads the networks from `reader`, failing on the first line that is not
+    /// a valid address or network.
+    pub fn from_reader(reader: impl BufRead, mode: Mode) -> io::Result<Self> {
+        Ok(Self {
+            networks: Arc::new(ArcSwap::from_pointee(read_cidr_set(reader)?)),
+            mode,
+        })
+    }
+
+    /// Reloads the networks from `path` for this filter and all its clones.
+    ///
+    /// The new list is parsed before it is swapped in, so on error the
+    /// current networks stay in place.
+    pub fn reload_from_path(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let networks = read_cidr_set(BufReader::new(File::open(path)?))?;
+        self.networks.store(Arc::new(networks));
+        Ok(())
+    }
+
+    /// The number of networks in the list.
+    pub fn len(&self) -> usize {
+        self.networks.load().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most specific listed network containing `ip`, if any.
+    pub fn lookup(&self, ip: &IpAddr) -> Option<IpNetwork> {
+        self.networks.load().longest_match(*ip)
+    }
+
+    fn decide_ip(&self, ip: &IpAddr) -> FilterDecision {
+        let listed = matches!(self.mode, Mode::BlackList);
+        match self.lookup(ip) {
+            Some(network) => {
+                FilterDecision::new(listed).with_reason(format!("network {}", network))
             }
+            None => FilterDecision::new(!listed),
         }
     }
+}
+
+/// `network` with its host bits cleared, e.g. `203.0.113.5/24` to `203.0.113.0/24`.
+fn normalize(network: IpNetwork) -> IpNetwork {
+    IpNetwork::new(network.network(), network.prefix()).expect("prefix is already valid")
+}
+
+fn read_cidr_set(reader: impl BufRead) -> io::Result<CidrSet> {
+    let mut networks = CidrSet::default();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let entry = line.trim();
+        if entry.is_empty() || entry.starts_with('#') {
+            continue;
+        }
+        let network = entry.parse::<IpNetwork>().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}: {}", number + 1, entry, err),
+            )
+        })?;
+        networks.insert(network);
+    }
+    Ok(networks)
+}
+
+impl NetworkFilter for CidrSetFilter {
+    /// Adds `ip` to the list until the next reload.
+    async fn block(&self, ip: impl IpAddrExt, _network: bool) {
+        let network = ip.to_network();
+        self.networks.rcu(|networks| {
+            let mut networks = CidrSet::clone(networks);
+            networks.insert(network);
+            networks
+        });
+    }
+
+    /// Removes `ip` from the list until the next reload.
+    async fn unblock(&self, ip: impl IpAddrExt, _network: bool) {
+        let network = ip.to_network();
+        self.networks.rcu(|networks| {
+            let mut networks = CidrSet::clone(networks);
+            networks.remove(network);
+            networks
+        });
+    }
+
+    async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+        self.decide_ip(&ip.to_ip_addr()).blocked
+    }
+
+    async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+        self.decide_ip(&ip.to_ip_addr())
+    }
+
+    fn decide_now(&self, ip: IpAddr) -> Option<FilterDecision> {
+        Some(self.decide_ip(&ip))
+    }
+
+    fn is_blocked_batch(&self, ips: &[IpAddr]) -> Vec<bool> {
+        let listed = matches!(self.mode, Mode::BlackList);
+        let networks = self.networks.load();
+        ips.iter()
+            .map(|ip| networks.longest_match(*ip).is_some() == listed)
+            .collect()
+    }
 
     fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
         create_ip_address_denied_response()
     }
+
+    fn mode(&self) -> Option<Mode> {
+        Some(self.mode.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[tokio::test]
+    async fn test_version_mismatch_does_not_panic() {
+        let v4 = IpFilter::<V4>::new(Mode::BlackList);
+        v4.block(Ipv6Addr::LOCALHOST, false).await;
+        v4.unblock(Ipv6Addr::LOCALHOST, false).await;
+        assert!(v4.addresses.is_empty());
+        assert!(!v4.is_blocked(Ipv6Addr::LOCALHOST).await);
+
+        let v6 = IpFilter::<V6>::new(Mode::WhiteList);
+        v6.block(Ipv4Addr::LOCALHOST, true).await;
+        assert!(v6.networks.is_empty());
+        assert!(v6.is_blocked(Ipv4Addr::LOCALHOST).await);
+    }
+
+    #[tokio::test]
+    async fn test_block_and_unblock() {
+        let filter = IpFilter::<V4>::new(Mode::BlackList);
+        let ip = Ipv4Addr::new(203, 0, 113, 5);
+
+        assert!(!filter.is_blocked(ip).await);
+        let before = BlockDate::now();
+        filter.block(ip, false).await;
+        assert!(filter.is_blocked(ip).await);
+        let blocked_since = filter.addresses.get(&IpAddr::V4(ip)).unwrap().date;
+        assert!(blocked_since >= before && blocked_since <= BlockDate::now());
+        filter.unblock(ip, false).await;
+        assert!(!filter.is_blocked(ip).await);
+    }
+
+    #[tokio::test]
+    async fn test_dual_filter_handles_both_families() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        let v4 = Ipv4Addr::new(203, 0, 113, 5);
+        let v6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+
+        filter.block(v4, false).await;
+        filter
+            .add_network(
+                "2001:db8::/32".parse().unwrap(),
+                "Blocked".to_string(),
+                "2021-09-01".parse().unwrap(),
+            )
+            .await;
+
+        assert!(filter.is_blocked(v4).await);
+        assert!(filter.is_blocked(v6).await);
+        assert!(!filter.is_blocked(Ipv4Addr::new(198, 51, 100, 1)).await);
+        assert!(
+            !filter
+                .is_blocked("2001:db9::1".parse::<Ipv6Addr>().unwrap())
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_from_strings() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+
+        filter
+            .add_ip_str(
+                "203.0.113.5",
+                "Spam".to_string(),
+                "2024-01-10".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+        filter
+            .add_network_str(
+                "198.51.100.0/24",
+                "Spam".to_string(),
+                "2024-01-10".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(filter
+            .add_ip_str(
+                "203.0.113.0/24",
+                "Spam".to_string(),
+                "2024-01-10".parse().unwrap()
+            )
+            .await
+            .is_err());
+        assert!(filter
+            .add_network_str(
+                "not a network",
+                "Spam".to_string(),
+                "2024-01-10".parse().unwrap()
+            )
+            .await
+            .is_err());
+
+        assert!(filter.is_blocked(Ipv4Addr::new(203, 0, 113, 5)).await);
+        assert!(filter.is_blocked(Ipv4Addr::new(198, 51, 100, 77)).await);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_insert() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        let meta = IpMetaData {
+            reason: "Feed".to_string(),
+            date: "2024-01-10".parse().unwrap(),
+            expires: None,
+        };
+
+        filter.add_ips((0..50_000u32).map(|i| {
+            let ip = IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i));
+            (ip, meta.clone())
+        }));
+        filter.add_networks([
+            ("198.51.100.0/24".parse().unwrap(), meta.clone()),
+            ("2001:db8::/32".parse().unwrap(), meta.clone()),
+        ]);
+
+        assert_eq!(filter.addresses.len(), 50_000);
+        assert_eq!(filter.networks.len(), 2);
+        assert!(filter.is_blocked(Ipv4Addr::new(10, 0, 195, 79)).await);
+        assert!(!filter.is_blocked(Ipv4Addr::new(10, 0, 195, 80)).await);
+        assert!(filter.is_blocked(Ipv4Addr::new(198, 51, 100, 7)).await);
+    }
+
+    #[test]
+    fn test_is_ip_blocked_sync() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        filter.add_ips([(
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5)),
+            IpMetaData {
+                reason: "Spam".to_string(),
+                date: BlockDate::now(),
+                expires: None,
+            },
+        )]);
+        assert!(filter.is_ip_blocked_sync(&IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5))));
+        assert!(!filter.is_ip_blocked_sync(&IpAddr::V4(Ipv4Addr::new(203, 0, 113, 6))));
+    }
+
+    #[test]
+    fn test_is_ip_blocked_sync_checks_family() {
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        // add_ips doesn't check the family, the lookup has to.
+        let filter = IpFilter::<V4>::new(Mode::BlackList);
+        filter.add_ips([(
+            v6,
+            IpMetaData {
+                reason: "Spam".to_string(),
+                date: BlockDate::now(),
+                expires: None,
+            },
+        )]);
+        assert!(!filter.is_ip_blocked_sync(&v6));
+        assert_eq!(
+            filter.decide_now(v6).map(|decision| decision.blocked),
+            Some(false)
+        );
+
+        let filter = IpFilter::<V6>::new(Mode::WhiteList);
+        let v4 = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5));
+        filter.add_ips([(
+            v4,
+            IpMetaData {
+                reason: "Trusted".to_string(),
+                date: BlockDate::now(),
+                expires: None,
+            },
+        )]);
+        assert!(filter.is_ip_blocked_sync(&v4));
+        assert_eq!(filter.is_blocked_batch(&[v4]), [true]);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_returns_metadata() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        filter
+            .add_network_str(
+                "198.51.100.0/24",
+                "Spam".to_string(),
+                "2024-01-10".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let meta = filter
+            .lookup(&IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7)))
+            .unwrap();
+        assert_eq!(meta.reason, "Spam");
+        assert_eq!(meta.date.to_string(), "2024-01-10");
+        assert!(filter
+            .lookup(&IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_count_and_iter() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        let date = BlockDate::now();
+        filter
+            .add_ip("203.0.113.5".parse().unwrap(), "Spam".to_string(), date)
+            .await;
+        filter
+            .add_network(
+                "198.51.100.0/24".parse().unwrap(),
+                "Abuse".to_string(),
+                date,
+            )
+            .await;
+        filter
+            .add_ip_for(
+                "203.0.113.6".parse().unwrap(),
+                "Gone".to_string(),
+                date,
+                Duration::ZERO,
+            )
+            .await;
+
+        assert_eq!(filter.blocked_count(), (1, 1));
+        let blocked: Vec<_> = filter
+            .iter_blocked()
+            .map(|(network, meta)| (network.to_string(), meta.reason))
+            .collect();
+        assert_eq!(
+            blocked,
+            [
+                ("203.0.113.5/32".to_string(), "Spam".to_string()),
+                ("198.51.100.0/24".to_string(), "Abuse".to_string()),
+            ]
+        );
+
+        filter
+            .add_network_for(
+                "192.0.2.0/24".parse().unwrap(),
+                "Gone".to_string(),
+                date,
+                Duration::ZERO,
+            )
+            .await;
+        let networks = filter.networks();
+        assert_eq!(networks.len(), 1);
+        assert_eq!(
+            networks[0].0,
+            "198.51.100.0/24".parse::<IpNetwork>().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_is_blocked_batch() {
+        let filter = IpFilter::<V4>::new(Mode::BlackList);
+        let date = BlockDate::now();
+        filter
+            .add_ip("203.0.113.5".parse().unwrap(), "Spam".to_string(), date)
+            .await;
+        filter
+            .add_network(
+                "198.51.100.0/24".parse().unwrap(),
+                "Abuse".to_string(),
+                date,
+            )
+            .await;
+        let ips: Vec<IpAddr> = ["203.0.113.5", "203.0.113.6", "198.51.100.9", "2001:db8::1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+
+        assert_eq!(filter.is_blocked_batch(&ips), [true, false, true, false]);
+        let dynamic: &dyn crate::network_filter_service::DynNetworkFilter = &filter;
+        assert_eq!(dynamic.is_blocked_batch(&ips), [true, false, true, false]);
+        for (ip, blocked) in ips.iter().zip(filter.is_blocked_batch(&ips)) {
+            assert_eq!(filter.is_blocked(*ip).await, blocked);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_ban() {
+        let filter = DualIpFilter::new(Mode::BlackList).enable_auto_ban(
+            3,
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+        );
+        let ip: IpAddr = "203.0.113.5".parse().unwrap();
+        let other: IpAddr = "203.0.113.6".parse().unwrap();
+
+        for _ in 0..3 {
+            filter.record_request(ip);
+            assert!(!filter.is_blocked(ip).await);
+        }
+        filter.record_request(other);
+        filter.record_request(ip);
+        let decision = filter.decide(ip).await;
+        assert!(decision.blocked);
+        assert!(decision.reason.unwrap().starts_with("auto-ban"));
+        assert!(decision.expires.is_some());
+        assert!(!filter.is_blocked(other).await);
+
+        // Requests aren't counted in whitelist mode, where listing allows.
+        let filter = DualIpFilter::new(Mode::WhiteList).enable_auto_ban(
+            1,
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+        );
+        for _ in 0..3 {
+            filter.record_request(ip);
+        }
+        assert!(filter.is_blocked(ip).await);
+        assert_eq!(filter.blocked_count(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_auto_ban_keeps_longer_blocks() {
+        let filter = DualIpFilter::new(Mode::BlackList).enable_auto_ban(
+            1,
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+        );
+        let permanent: IpAddr = "203.0.113.5".parse().unwrap();
+        let longer: IpAddr = "203.0.113.6".parse().unwrap();
+        let shorter: IpAddr = "203.0.113.7".parse().unwrap();
+        let date = || "2024-01-10".parse().unwrap();
+        filter.add_ip(permanent, "Abuse".to_string(), date()).await;
+        let day = Duration::from_secs(86_400);
+        filter
+            .add_ip_for(longer, "Abuse".to_string(), date(), day)
+            .await;
+        filter
+            .add_ip_for(shorter, "Abuse".to_string(), date(), Duration::from_secs(5))
+            .await;
+
+        for ip in [permanent, longer, shorter] {
+            filter.record_request(ip);
+            filter.record_request(ip);
+        }
+        assert_eq!(filter.addresses.get(&permanent).unwrap().expires, None);
+        let reason = |ip| filter.addresses.get(&ip).unwrap().reason.clone();
+        assert_eq!(reason(longer), "Abuse");
+        assert!(reason(shorter).starts_with("auto-ban"));
+    }
+
+    #[tokio::test]
+    async fn test_remove_reports_listed() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        let ip: IpAddr = "203.0.113.5".parse().unwrap();
+        let expired: IpAddr = "203.0.113.6".parse().unwrap();
+        let date = || "2024-01-10".parse().unwrap();
+        filter.add_ip(ip, "Abuse".to_string(), date()).await;
+        filter
+            .add_ip_for(expired, "Abuse".to_string(), date(), Duration::ZERO)
+            .await;
+        filter
+            .add_network(
+                "198.51.100.7/24".parse().unwrap(),
+                "Abuse".to_string(),
+                date(),
+            )
+            .await;
+
+        assert!(filter.remove_ip(ip));
+        assert!(!filter.remove_ip(ip));
+        assert!(!filter.remove_ip(expired));
+        assert!(filter.remove_network("198.51.100.0/24".parse().unwrap()));
+        assert!(!filter.remove_network("198.51.100.0/24".parse().unwrap()));
+        assert_eq!(filter.blocked_count(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_many_networks_longest_prefix() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        let meta = |reason: String| IpMetaData {
+            reason,
+            date: BlockDate::now(),
+            expires: None,
+        };
+        filter.add_networks((0..16u8).flat_map(|b| {
+            (0..=255u8).map(move |c| {
+                let network = Ipv4Network::new(Ipv4Addr::new(10, b, c, 0), 24).unwrap();
+                (IpNetwork::V4(network), meta(format!("feed {}.{}", b, c)))
+            })
+        }));
+        filter.add_networks([("10.0.0.0/8".parse().unwrap(), meta("wide".to_string()))]);
+        filter
+            .add_network(
+                "10.3.7.9/28".parse().unwrap(),
+                "narrow".to_string(),
+                BlockDate::now(),
+            )
+            .await;
+        assert_eq!(filter.blocked_count(), (0, 16 * 256 + 2));
+
+        let reason = |ip: &str| filter.lookup(&ip.parse().unwrap()).map(|meta| meta.reason);
+        assert_eq!(reason("10.5.200.1").as_deref(), Some("feed 5.200"));
+        assert_eq!(reason("10.3.7.1").as_deref(), Some("narrow"));
+        assert_eq!(reason("10.3.7.17").as_deref(), Some("feed 3.7"));
+        assert_eq!(reason("10.200.0.1").as_deref(), Some("wide"));
+        assert_eq!(reason("11.0.0.1"), None);
+
+        // Exact addresses still win over any network.
+        filter
+            .add_ip(
+                "10.5.200.1".parse().unwrap(),
+                "exact".to_string(),
+                BlockDate::now(),
+            )
+            .await;
+        assert_eq!(reason("10.5.200.1").as_deref(), Some("exact"));
+
+        filter
+            .unblock("10.3.7.0/28".parse::<Ipv4Network>().unwrap(), true)
+            .await;
+        assert_eq!(reason("10.3.7.1").as_deref(), Some("feed 3.7"));
+    }
+
+    #[tokio::test]
+    async fn test_add_networks_from_lines() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        let blocklist = "# threat feed\n\n203.0.113.0/24\n  2001:db8::/32  \n192.0.2.1\nbogus\n";
+
+        let (added, errors) = filter
+            .add_networks_from_lines(
+                blocklist.lines().map(String::from),
+                "Feed",
+                "2024-01-10".parse().unwrap(),
+            )
+            .await;
+
+        assert_eq!(added, 3);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "bogus");
+        assert!(filter.is_blocked(Ipv4Addr::new(203, 0, 113, 9)).await);
+        assert!(filter.is_blocked(Ipv4Addr::new(192, 0, 2, 1)).await);
+        assert!(
+            filter
+                .is_blocked("2001:db8::5".parse::<Ipv6Addr>().unwrap())
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_temporary_blocks_expire() {
+        let filter = DualIpFilter::new(Mode::BlackList);
+        let banned = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5));
+        let expired = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 6));
+
+        filter
+            .add_ip_for(
+                banned,
+                "Abuse".to_string(),
+                "2024-01-10".parse().unwrap(),
+                Duration::from_secs(3600),
+            )
+            .await;
+        filter
+            .add_ip_for(
+                expired,
+                "Abuse".to_string(),
+                "2024-01-10".parse().unwrap(),
+                Duration::ZERO,
+            )
+            .await;
+        filter
+            .add_network_for(
+                "198.51.100.0/24".parse().unwrap(),
+                "Abuse".to_string(),
+                "2024-01-10".parse().unwrap(),
+                Duration::ZERO,
+            )
+            .await;
+
+        assert!(filter.is_blocked(banned).await);
+        assert!(!filter.is_blocked(expired).await);
+        assert!(!filter.is_blocked(Ipv4Addr::new(198, 51, 100, 1)).await);
+
+        // Expired entries are evicted on lookup.
+        assert!(!filter.addresses.contains_key(&expired));
+        assert!(filter.networks.is_empty());
+        assert!(filter.addresses.contains_key(&banned));
+    }
+
+    #[tokio::test]
+    async fn test_export_import_round_trip() {
+        let filter = DualIpFilter::new(Mode::WhiteList);
+        let trusted = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 5));
+        filter
+            .add_ip(trusted, "Office".to_string(), "2024-01-10".parse().unwrap())
+            .await;
+        filter
+            .add_network_for(
+                "2001:db8::/32".parse().unwrap(),
+                "Partner".to_string(),
+                "2024-01-10".parse().unwrap(),
+                Duration::from_secs(3600),
+            )
+            .await;
+        filter
+            .add_ip_for(
+                IpAddr::V4(Ipv4Addr::new(203, 0, 113, 6)),
+                "Expired".to_string(),
+                "2024-01-10".parse().unwrap(),
+                Duration::ZERO,
+            )
+            .await;
+
+        let snapshot = filter.export();
+        assert_eq!(snapshot.addresses.len(), 1);
+        assert_eq!(snapshot.networks.len(), 1);
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored = DualIpFilter::import(serde_json::from_str(&json).unwrap());
+
+        assert_eq!(restored.mode, Mode::WhiteList);
+        assert_eq!(restored.export(), snapshot);
+        for ip in [
+            trusted,
+            "2001:db8::1".parse().unwrap(),
+            IpAddr::V4(Ipv4Addr::new(203, 0, 113, 6)),
+            IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)),
+        ] {
+            assert_eq!(restored.is_blocked(ip).await, filter.is_blocked(ip).await);
+        }
+    }
+
+    const BLOCKLIST: &str = "# threat feed
+203.0.113.0/24
+
+203.0.113.128/25
+192.0.2.1
+2001:db8::/32
+";
+
+    #[tokio::test]
+    async fn test_cidr_set_filter() {
+        let filter = CidrSetFilter::from_reader(BLOCKLIST.as_bytes(), Mode::BlackList).unwrap();
+        assert_eq!(filter.len(), 4);
+
+        assert!(filter.is_blocked(Ipv4Addr::new(203, 0, 113, 1)).await);
+        assert!(filter.is_blocked(Ipv4Addr::new(192, 0, 2, 1)).await);
+        assert!(!filter.is_blocked(Ipv4Addr::new(192, 0, 2, 2)).await);
+        assert!(
+            filter
+                .is_blocked("2001:db8::1".parse::<Ipv6Addr>().unwrap())
+                .await
+        );
+        assert_eq!(
+            filter.lookup(&IpAddr::V4(Ipv4Addr::new(203, 0, 113, 200))),
+            Some("203.0.113.128/25".parse().unwrap())
+        );
+        assert_eq!(
+            filter
+                .decide(Ipv4Addr::new(203, 0, 113, 1))
+                .await
+                .reason
+                .as_deref(),
+            Some("network 203.0.113.0/24")
+        );
+
+        filter.block(Ipv4Addr::new(198, 51, 100, 1), false).await;
+        assert!(filter.is_blocked(Ipv4Addr::new(198, 51, 100, 1)).await);
+        filter.unblock(Ipv4Addr::new(192, 0, 2, 1), false).await;
+        assert!(!filter.is_blocked(Ipv4Addr::new(192, 0, 2, 1)).await);
+
+        let whitelist = CidrSetFilter::from_reader(BLOCKLIST.as_bytes(), Mode::WhiteList).unwrap();
+        assert!(!whitelist.is_blocked(Ipv4Addr::new(203, 0, 113, 1)).await);
+        assert!(whitelist.is_blocked(Ipv4Addr::new(198, 51, 100, 1)).await);
+    }
+
+    #[tokio::test]
+    async fn test_cidr_set_filter_reload() {
+        let path = std::env::temp_dir().join(format!(
+            "tower-ipfilter-blocklist-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&path, "203.0.113.0/24\n").unwrap();
+        let filter = CidrSetFilter::from_path(&path, Mode::BlackList).unwrap();
+        let clone = filter.clone();
+        assert!(clone.is_blocked(Ipv4Addr::new(203, 0, 113, 1)).await);
+
+        std::fs::write(&path, "198.51.100.0/24\nnot a network\n").unwrap();
+        let err = filter.reload_from_path(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(clone.is_blocked(Ipv4Addr::new(203, 0, 113, 1)).await);
+
+        std::fs::write(&path, "198.51.100.0/24\n").unwrap();
+        filter.reload_from_path(&path).unwrap();
+        assert!(!clone.is_blocked(Ipv4Addr::new(203, 0, 113, 1)).await);
+        assert!(clone.is_blocked(Ipv4Addr::new(198, 51, 100, 1)).await);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_block_date_parsing() {
+        let date: BlockDate = "2024-01-10".parse().unwrap();
+        assert_eq!(date, BlockDate::from_ymd(2024, 1, 10).unwrap());
+        assert_eq!(date.to_string(), "2024-01-10");
+        assert_eq!(
+            date.as_system_time(),
+            UNIX_EPOCH + Duration::from_secs(1_704_844_800)
+        );
+
+        let timestamp: BlockDate = "2024-02-29T08:30:05Z".parse().unwrap();
+        assert_eq!(timestamp.to_string(), "2024-02-29T08:30:05Z");
+        assert!(date < timestamp);
+        assert_eq!(
+            "1969-12-31".parse::<BlockDate>().unwrap().to_string(),
+            "1969-12-31"
+        );
+
+        // Fractions of a second are dropped, so dates survive a round trip.
+        let now = BlockDate::now();
+        assert_eq!(now.to_string().parse::<BlockDate>().unwrap(), now);
+        let before_epoch = BlockDate::from(UNIX_EPOCH - Duration::from_millis(500));
+        assert_eq!(before_epoch.to_string(), "1969-12-31T23:59:59Z");
+
+        for invalid in [
+            "2021-9-1",
+            "yesterday",
+            "2023-02-29",
+            "2024-13-01",
+            "2024-01-10T25:00:00Z",
+            "2024-01-10T08:30:05",
+            "2024-01-10-01",
+            "+024-01-10",
+        ] {
+            assert!(invalid.parse::<BlockDate>().is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            "yesterday".parse::<BlockDate>().unwrap_err().to_string(),
+            "invalid date \"yesterday\", expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ"
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_block_date_chrono() {
+        use chrono::{DateTime, TimeZone, Utc};
+
+        let time = Utc.with_ymd_and_hms(2024, 1, 10, 8, 30, 5).unwrap();
+        let date = BlockDate::from(time);
+        assert_eq!(date.to_string(), "2024-01-10T08:30:05Z");
+        assert_eq!(DateTime::<Utc>::from(date), time);
+    }
 }
@@ -1,9 +1,16 @@
 pub mod types;
+pub mod error;
 mod compress;
 mod extract;
+#[cfg(feature = "mmdb")]
+mod mmdb;
 mod body;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod geo_filter;
+pub mod asn_filter;
 pub mod ip_filter;
+pub mod allowlist;
 pub mod network_filter_service;
 pub mod connection_info_service;
 
@@ -60,15 +67,11 @@ mod tests {
         //});
 
 
-        GeoIpv4Filter {
-            networks: ip_networks,
-            addresses: DashMap::new(),
-            countries: DashMap::new(),
-            mode: Default::default(),
-        }
+        GeoIpv4Filter::from_networks(Default::default(), ip_networks)
     }
 
     #[tokio::test]
+    #[allow(deprecated)]
     async fn test_get_country_for_ip() {
         let service = create_test_geo_ip_service();
 
@@ -100,6 +103,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(deprecated)]
     async fn test_get_country_for_ip_edge_cases() {
         let service = create_test_geo_ip_service();
 
@@ -152,4 +156,30 @@ mod tests {
         assert!(!service.is_ip_blocked(&Ipv4Addr::from_str("10.0.0.1").unwrap()).await); // US
         //assert!(service.is_ip_blocked(&Ipv4Addr::from_str("2001:db8::1").unwrap()).await); // Japan
     }
+
+    #[tokio::test]
+    async fn test_clone_shares_networks() {
+        let service = create_test_geo_ip_service();
+        let clone = service.clone();
+        assert!(std::sync::Arc::ptr_eq(&service.database(), &clone.database()));
+
+        // Replacing the networks is visible through every clone.
+        let networks = DashMap::new();
+        networks.insert(Ipv4Network::from_str("8.8.8.0/24").unwrap(), CountryLocation {
+            geoname_id: 2,
+            locale_code: "EN".to_string(),
+            continent_code: "NA".to_string(),
+            continent_name: "North America".to_string(),
+            country_iso_code: Some("US".to_string()),
+            country_name: Some("United States".to_string()),
+            is_in_european_union: false,
+        });
+        service.replace_networks(networks);
+
+        assert_eq!(clone.country_for_ip(&Ipv4Addr::from_str("10.0.0.1").unwrap()), None);
+        assert_eq!(
+            clone.country_for_ip(&Ipv4Addr::from_str("8.8.8.8").unwrap()).unwrap().country_name,
+            Some("United States".to_string())
+        );
+    }
 }
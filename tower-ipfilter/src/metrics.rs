@@ -0,0 +1,71 @@
+//! Metrics recorded by [`Filter`](crate::network_filter_service::Filter) with
+//! the `metrics` feature enabled.
+//!
+//! They go through the [`metrics`](::metrics) crate, so install a recorder such
+//! as `metrics-exporter-prometheus` to export them. Labels only take a fixed
+//! set of values to keep the number of series bounded.
+
+use std::time::Duration;
+
+use crate::network_filter_service::FilterDecision;
+
+/// Counter of filtered requests, labelled `decision`: `allowed` or `blocked`.
+///
+/// Report-only blocks count as `blocked`.
+pub const REQUESTS_TOTAL: &str = "ipfilter_requests_total";
+
+/// Counter of blocked requests, labelled `country` with the ISO code of the
+/// address's country, or `unknown` if it didn't resolve to one.
+pub const BLOCKED_TOTAL: &str = "ipfilter_blocked_total";
+
+/// Histogram of the time spent deciding on a request, in seconds.
+pub const LOOKUP_DURATION_SECONDS: &str = "ipfilter_lookup_duration_seconds";
+
+/// Records a decision, `lookup` is `None` when there was no address to look up.
+pub(crate) fn record_decision(decision: &FilterDecision, lookup: Option<Duration>) {
+    let label = if decision.blocked { "blocked" } else { "allowed" };
+    ::metrics::counter!(REQUESTS_TOTAL, "decision" => label).increment(1);
+    if decision.blocked {
+        ::metrics::counter!(BLOCKED_TOTAL, "country" => country_label(decision)).increment(1);
+    }
+    if let Some(lookup) = lookup {
+        ::metrics::histogram!(LOOKUP_DURATION_SECONDS).record(lookup.as_secs_f64());
+    }
+}
+
+/// The decision's two-letter country code, anything else is bucketed as `unknown`.
+fn country_label(decision: &FilterDecision) -> String {
+    decision
+        .country
+        .as_ref()
+        .and_then(|country| country.country_iso_code.as_deref())
+        .filter(|code| code.len() == 2 && code.bytes().all(|b| b.is_ascii_uppercase()))
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::CountryLocation;
+
+    #[test]
+    fn test_country_label() {
+        let with_code = |code: Option<&str>| {
+            FilterDecision::new(true).with_country(CountryLocation {
+                geoname_id: 1,
+                locale_code: "en".to_string(),
+                continent_code: "EU".to_string(),
+                continent_name: "Europe".to_string(),
+                country_iso_code: code.map(str::to_string),
+                country_name: Some("France".to_string()),
+                is_in_european_union: true,
+            })
+        };
+
+        assert_eq!(country_label(&with_code(Some("FR"))), "FR");
+        assert_eq!(country_label(&with_code(Some("not a code"))), "unknown");
+        assert_eq!(country_label(&with_code(None)), "unknown");
+        assert_eq!(country_label(&FilterDecision::new(true)), "unknown");
+    }
+}
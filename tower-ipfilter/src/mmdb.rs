@@ -0,0 +1,119 @@
+use crate::error::GeoLoadError;
+use crate::types::CountryLocation;
+use dashmap::DashMap;
+use ipnetwork::{IpNetwork, Ipv4Network};
+use maxminddb::{geoip2, Reader};
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+const LOCALE: &str = "en";
+
+/// Reads every IPv4 network from a MaxMind country database (e.g. `GeoLite2-Country.mmdb`).
+pub fn load_mmdb_networks(
+    path: &Path,
+) -> Result<DashMap<Ipv4Network, CountryLocation>, GeoLoadError> {
+    let reader = Reader::open_readfile(path)?;
+    let networks = DashMap::new();
+    let mut skipped = 0;
+
+    let all = IpNetwork::V4(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).expect("valid prefix"));
+    for item in reader.within::<geoip2::Country>(all)? {
+        let item = item?;
+        let IpNetwork::V4(network) = item.ip_net else {
+            continue;
+        };
+        match to_location(&item.info) {
+            Some(location) => {
+                networks.insert(network, location);
+            }
+            None => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        tracing::debug!(
+            "Skipped {} networks without a country geoname_id in {}",
+            skipped,
+            path.display()
+        );
+    }
+
+    Ok(networks)
+}
+
+/// Converts a database record to the same shape the CSV archives produce.
+///
+/// Records without a country or its geoname_id, e.g. networks only known by
+/// their registered country, have no location.
+fn to_location(record: &geoip2::Country) -> Option<CountryLocation> {
+    let country = record.country.as_ref()?;
+    let continent = record.continent.as_ref();
+    let name = |names: Option<&std::collections::BTreeMap<&str, &str>>| {
+        names
+            .and_then(|names| names.get(LOCALE))
+            .map(|name| name.to_string())
+    };
+
+    Some(CountryLocation {
+        geoname_id: country.geoname_id?,
+        locale_code: LOCALE.to_string(),
+        continent_code: continent
+            .and_then(|continent| continent.code)
+            .unwrap_or_default()
+            .to_string(),
+        continent_name: name(continent.and_then(|continent| continent.names.as_ref()))
+            .unwrap_or_default(),
+        country_iso_code: country.iso_code.map(str::to_string),
+        country_name: name(country.names.as_ref()),
+        is_in_european_union: country.is_in_european_union.unwrap_or(false),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maxminddb::geoip2::country;
+    use std::collections::BTreeMap;
+
+    fn record(geoname_id: Option<u32>) -> geoip2::Country<'static> {
+        geoip2::Country {
+            continent: Some(country::Continent {
+                code: Some("EU"),
+                geoname_id: Some(6255148),
+                names: Some(BTreeMap::from([("en", "Europe"), ("de", "Europa")])),
+            }),
+            country: Some(country::Country {
+                geoname_id,
+                is_in_european_union: Some(true),
+                iso_code: Some("FR"),
+                names: Some(BTreeMap::from([("en", "France"), ("de", "Frankreich")])),
+            }),
+            registered_country: None,
+            represented_country: None,
+            traits: None,
+        }
+    }
+
+    #[test]
+    fn test_to_location() {
+        let location = to_location(&record(Some(3017382))).unwrap();
+        assert_eq!(
+            location,
+            CountryLocation {
+                geoname_id: 3017382,
+                locale_code: "en".to_string(),
+                continent_code: "EU".to_string(),
+                continent_name: "Europe".to_string(),
+                country_iso_code: Some("FR".to_string()),
+                country_name: Some("France".to_string()),
+                is_in_european_union: true,
+            }
+        );
+
+        assert_eq!(to_location(&record(None)), None);
+        let without_country = geoip2::Country {
+            country: None,
+            ..record(Some(3017382))
+        };
+        assert_eq!(to_location(&without_country), None);
+    }
+}
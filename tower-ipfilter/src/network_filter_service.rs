@@ -1,69 +1,612 @@
 use crate::{
-    body::{create_ip_not_found_response, IpResponseBody}, connection_info_service::ConnectionInfo, geo_filter::IpAddrExt
+    body::{create_access_denied_response_json, create_ip_not_found_response, create_ip_not_found_response_json, create_ip_not_identified_response, create_ip_not_identified_response_json, with_retry_after, IpResponseBody, NoBody}, connection_info_service::{AddConnectionInfo, AddConnectionInfoLayer, ConnectionInfo}, geo_filter::IpAddrExt, types::{CountryLocation, Mode}
 };
 use bytes::Bytes;
-use futures_lite::FutureExt;
+use futures_lite::{future::Boxed, FutureExt};
+use pin_project_lite::pin_project;
 use http::{Request, Response};
 use http_body::Body;
-use std::{future::Future, sync::Arc, task::{Context, Poll}};
+use std::{future::{Future, Ready}, net::IpAddr, pin::Pin, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, task::{Context, Poll, Waker}, time::{Duration, Instant, SystemTime}};
 use tower_service::Service;
+use tracing::{instrument::Instrumented, Instrument, Level};
 
 pub trait NetworkFilter: Send + Sync + 'static {
     fn block(&self, ip: impl IpAddrExt, network: bool) -> impl Future<Output = ()> + Send;
     fn unblock(&self, ip: impl IpAddrExt, network: bool) -> impl Future<Output = ()> + Send;
     fn is_blocked(&self, ip: impl IpAddrExt) -> impl Future<Output = bool> + Send;
     fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>>;
+
+    /// Decides whether `ip` is blocked, along with what the filter learned about it.
+    ///
+    /// Filters that resolve extra data during the check, such as the country,
+    /// should override this so callers don't need a second lookup.
+    fn decide(&self, ip: impl IpAddrExt) -> impl Future<Output = FilterDecision> + Send {
+        async move { FilterDecision::new(self.is_blocked(ip).await) }
+    }
+
+    /// Decides on `ip` without waiting, for filters whose lookups never wait.
+    ///
+    /// [`Filter`] answers requests decided here without allocating. Returns
+    /// `None` by default, in which case [`NetworkFilter::decide`] is awaited
+    /// instead; filters overriding this must decide the same way as `decide`.
+    fn decide_now(&self, _ip: IpAddr) -> Option<FilterDecision> {
+        None
+    }
+
+    /// Whether the filter can take requests, polled by [`Filter`] before the inner service.
+    ///
+    /// Filters that update their data in place return `Poll::Pending` during
+    /// the update, see [`ReloadGate`].
+    fn poll_ready(&self, _cx: &mut Context<'_>) -> Poll<()> {
+        Poll::Ready(())
+    }
+
+    /// Checks many addresses at once, for offline analysis rather than requests.
+    ///
+    /// The default runs [`NetworkFilter::is_blocked`] for each address to
+    /// completion on the current thread. Filters override it to check every
+    /// address against a single snapshot of their data.
+    fn is_blocked_batch(&self, ips: &[IpAddr]) -> Vec<bool> {
+        ips.iter()
+            .map(|ip| futures_lite::future::block_on(self.is_blocked(*ip)))
+            .collect()
+    }
+
+    /// Called by [`Filter`] with the client address of each request, before
+    /// deciding on it. Does nothing by default, see
+    /// [`IpFilter::enable_auto_ban`](crate::ip_filter::IpFilter::enable_auto_ban).
+    fn record_request(&self, _ip: IpAddr) {}
+
+    /// Whether the filter lists the addresses to block or the ones to allow,
+    /// reported by [`FilterLayerBuilder::log_denials`]. `None` by default, for
+    /// filters without a list.
+    fn mode(&self) -> Option<Mode> {
+        None
+    }
+}
+
+/// Marks a filter's data as being updated, holding back requests until the
+/// update is complete.
+///
+/// [`Filter::poll_ready`](Service::poll_ready) returns `Poll::Pending` while any
+/// [`ReloadGuard`] is alive and wakes the waiting tasks once the last one is dropped.
+#[derive(Debug, Default)]
+pub struct ReloadGate {
+    updates: AtomicUsize,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl ReloadGate {
+    /// Starts an update, which lasts until the returned guard is dropped.
+    pub fn begin(&self) -> ReloadGuard<'_> {
+        self.updates.fetch_add(1, Ordering::SeqCst);
+        ReloadGuard { gate: self }
+    }
+
+    pub fn is_reloading(&self) -> bool {
+        self.updates.load(Ordering::SeqCst) > 0
+    }
+
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_reloading() {
+            return Poll::Ready(());
+        }
+        self.wakers.lock().unwrap().push(cx.waker().clone());
+        // The update may have finished before the waker was registered.
+        if self.is_reloading() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+/// Returned by [`ReloadGate::begin`].
+#[derive(Debug)]
+pub struct ReloadGuard<'a> {
+    gate: &'a ReloadGate,
+}
+
+impl Drop for ReloadGuard<'_> {
+    fn drop(&mut self) {
+        if self.gate.updates.fetch_sub(1, Ordering::SeqCst) == 1 {
+            for waker in self.gate.wakers.lock().unwrap().drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The outcome of [`NetworkFilter::decide`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterDecision {
+    pub blocked: bool,
+    /// The rule that matched the address, if any.
+    pub reason: Option<String>,
+    /// The country the address resolved to, for filters that look it up.
+    pub country: Option<Arc<CountryLocation>>,
+    /// When the matching rule expires, `None` for a permanent rule.
+    ///
+    /// A blocked request with an expiry is answered with `429 Too Many Requests`
+    /// and a `Retry-After` header instead of `403 Forbidden`.
+    pub expires: Option<SystemTime>,
+}
+
+impl FilterDecision {
+    pub fn new(blocked: bool) -> Self {
+        Self {
+            blocked,
+            reason: None,
+            country: None,
+            expires: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    pub fn with_country(mut self, country: impl Into<Arc<CountryLocation>>) -> Self {
+        self.country = Some(country.into());
+        self
+    }
+
+    pub fn with_expiry(mut self, expires: Option<SystemTime>) -> Self {
+        self.expires = expires;
+        self
+    }
+}
+
+/// Passed to the hook set with [`FilterLayerBuilder::on_decision`] once per request.
+#[derive(Debug)]
+pub struct FilterEvent<'a> {
+    /// The client address, `None` when the request carried no [`ConnectionInfo`].
+    pub ip: Option<IpAddr>,
+    pub decision: &'a FilterDecision,
+    /// Time spent deciding.
+    pub elapsed: Duration,
+}
+
+pub type DecisionHook = Arc<dyn Fn(&FilterEvent<'_>) + Send + Sync>;
+
+/// Added by [`Filter`] to the extensions of every response it decided on,
+/// denied or forwarded, for layers running after it such as access logs.
+///
+/// Responses to requests skipped with [`FilterLayerBuilder::skip_if`] carry none.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterOutcome {
+    /// The client address, `None` when the request carried no [`ConnectionInfo`].
+    pub ip: Option<IpAddr>,
+    pub decision: FilterDecision,
+}
+
+impl FilterOutcome {
+    /// Adds this outcome to the extensions of `res`.
+    fn attach<B>(self, mut res: Response<B>) -> Response<B> {
+        res.extensions_mut().insert(self);
+        res
+    }
+}
+
+/// Decides whether a request skips the filter, see [`FilterLayerBuilder::skip_if`].
+pub type BypassPredicate = Arc<dyn Fn(&http::request::Parts) -> bool + Send + Sync>;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Object-safe counterpart of [`NetworkFilter`], implemented for every filter.
+///
+/// Use it to pick a filter at runtime, e.g. `Arc<dyn DynNetworkFilter>`, which
+/// can be passed to [`FilterLayer::new`] like any concrete filter.
+pub trait DynNetworkFilter: Send + Sync + 'static {
+    fn block_dyn(&self, ip: IpAddr, network: bool) -> BoxFuture<'_, ()>;
+    fn unblock_dyn(&self, ip: IpAddr, network: bool) -> BoxFuture<'_, ()>;
+    fn is_blocked_dyn(&self, ip: IpAddr) -> BoxFuture<'_, bool>;
+    fn decide_dyn(&self, ip: IpAddr) -> BoxFuture<'_, FilterDecision>;
+    fn decide_now_dyn(&self, ip: IpAddr) -> Option<FilterDecision>;
+    fn poll_ready_dyn(&self, cx: &mut Context<'_>) -> Poll<()>;
+    fn is_blocked_batch_dyn(&self, ips: &[IpAddr]) -> Vec<bool>;
+    fn record_request_dyn(&self, ip: IpAddr);
+    fn mode_dyn(&self) -> Option<Mode>;
+    fn to_denied_response_dyn(&self) -> http::Response<IpResponseBody<NoBody>>;
+}
+
+impl<F: NetworkFilter> DynNetworkFilter for F {
+    fn block_dyn(&self, ip: IpAddr, network: bool) -> BoxFuture<'_, ()> {
+        Box::pin(self.block(ip, network))
+    }
+
+    fn unblock_dyn(&self, ip: IpAddr, network: bool) -> BoxFuture<'_, ()> {
+        Box::pin(self.unblock(ip, network))
+    }
+
+    fn is_blocked_dyn(&self, ip: IpAddr) -> BoxFuture<'_, bool> {
+        Box::pin(self.is_blocked(ip))
+    }
+
+    fn decide_dyn(&self, ip: IpAddr) -> BoxFuture<'_, FilterDecision> {
+        Box::pin(self.decide(ip))
+    }
+
+    fn decide_now_dyn(&self, ip: IpAddr) -> Option<FilterDecision> {
+        self.decide_now(ip)
+    }
+
+    fn poll_ready_dyn(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_ready(cx)
+    }
+
+    fn is_blocked_batch_dyn(&self, ips: &[IpAddr]) -> Vec<bool> {
+        self.is_blocked_batch(ips)
+    }
+
+    fn record_request_dyn(&self, ip: IpAddr) {
+        self.record_request(ip)
+    }
+
+    fn to_denied_response_dyn(&self) -> http::Response<IpResponseBody<NoBody>> {
+        self.to_denied_response()
+    }
+
+    fn mode_dyn(&self) -> Option<Mode> {
+        self.mode()
+    }
+}
+
+impl NetworkFilter for dyn DynNetworkFilter {
+    async fn block(&self, ip: impl IpAddrExt, network: bool) {
+        self.block_dyn(ip.to_ip_addr(), network).await
+    }
+
+    async fn unblock(&self, ip: impl IpAddrExt, network: bool) {
+        self.unblock_dyn(ip.to_ip_addr(), network).await
+    }
+
+    async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+        self.is_blocked_dyn(ip.to_ip_addr()).await
+    }
+
+    async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+        self.decide_dyn(ip.to_ip_addr()).await
+    }
+
+    fn decide_now(&self, ip: IpAddr) -> Option<FilterDecision> {
+        self.decide_now_dyn(ip)
+    }
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_ready_dyn(cx)
+    }
+
+    fn is_blocked_batch(&self, ips: &[IpAddr]) -> Vec<bool> {
+        self.is_blocked_batch_dyn(ips)
+    }
+
+    fn record_request(&self, ip: IpAddr) {
+        self.record_request_dyn(ip)
+    }
+
+    fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
+        self.to_denied_response_dyn().map(IpResponseBody::into_body)
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        self.mode_dyn()
+    }
+}
+
+/// What [`Filter`] does with a request that carries no [`ConnectionInfo`].
+///
+/// The response for `Block` and `Reject` can be replaced with
+/// [`FilterLayerBuilder::missing_ip_response`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MissingIpPolicy {
+    /// Deny the request with `403 Forbidden`, like a blocked address.
+    #[default]
+    Block,
+    /// Answer `400 Bad Request`: the client couldn't be identified, which is
+    /// not the same as being blocked.
+    Reject,
+    /// Forward the request unfiltered.
+    Allow,
+}
+
+/// Builds the response for requests without a client address, see
+/// [`FilterLayerBuilder::missing_ip_response`].
+pub type MissingIpResponse = Arc<dyn Fn() -> Response<Bytes> + Send + Sync>;
+
+/// Whether [`Filter`] acts on its decisions.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Enforcement {
+    /// Deny blocked requests.
+    #[default]
+    Enforce,
+    /// Forward every request, only logging and reporting what would be blocked,
+    /// e.g. to try out a new list before enforcing it.
+    ReportOnly,
+}
+
+/// The body format of the responses [`Filter`] sends for denied requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ResponseFormat {
+    /// The filter's own `text/plain` message, see [`NetworkFilter::to_denied_response`].
+    #[default]
+    PlainText,
+    /// An `application/json` body such as
+    /// `{"error":"access_denied","reason":"country","detail":"US"}`.
+    Json,
+}
+
+/// Options shared by [`FilterLayer`] and the [`Filter`] services it creates.
+#[derive(Clone, Default)]
+struct FilterConfig {
+    on_decision: Option<DecisionHook>,
+    bypass: Option<BypassPredicate>,
+    missing_ip: MissingIpPolicy,
+    missing_ip_response: Option<MissingIpResponse>,
+    enforcement: Enforcement,
+    response_format: ResponseFormat,
+    denial_log: Option<Level>,
 }
 
-#[derive(Clone)]
 // Generic Filter service
-pub struct Filter<S, F> {
+pub struct Filter<S, F: ?Sized> {
     inner: S,
     filter: Arc<F>,
+    config: FilterConfig,
+}
+
+impl<S: Clone, F: ?Sized> Clone for Filter<S, F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            filter: self.filter.clone(),
+            config: self.config.clone(),
+        }
+    }
 }
 
 impl<S, F> Filter<S, F>
 where
-    F: NetworkFilter,
+    F: NetworkFilter + ?Sized,
 {
     pub fn new(inner: S, filter: Arc<F>) -> Self {
-        Self { inner, filter }
+        Self {
+            inner,
+            filter,
+            config: FilterConfig::default(),
+        }
     }
 
     pub fn layer(filter: Arc<F>) -> FilterLayer<F> {
-        FilterLayer { filter }
+        FilterLayer::new(filter)
     }
 }
 
-#[derive(Clone)]
-pub struct FilterLayer<F> {
+pub struct FilterLayer<F: ?Sized> {
     filter: Arc<F>,
+    config: FilterConfig,
+}
+
+impl<F: ?Sized> Clone for FilterLayer<F> {
+    fn clone(&self) -> Self {
+        Self {
+            filter: self.filter.clone(),
+            config: self.config.clone(),
+        }
+    }
 }
 
 impl<F> FilterLayer<F>
 where
-    F: NetworkFilter,
+    F: NetworkFilter + ?Sized,
 {
+    /// Creates a layer with the default options, see [`FilterLayer::builder`].
     pub fn new(filter: Arc<F>) -> Self {
-        Self { filter }
+        Self::builder(filter).build()
+    }
+
+    pub fn builder(filter: Arc<F>) -> FilterLayerBuilder<F> {
+        FilterLayerBuilder {
+            filter,
+            config: FilterConfig::default(),
+            strict: false,
+        }
+    }
+}
+
+/// Configures a [`FilterLayer`], created with [`FilterLayer::builder`].
+pub struct FilterLayerBuilder<F: ?Sized> {
+    filter: Arc<F>,
+    config: FilterConfig,
+    strict: bool,
+}
+
+impl<F> FilterLayerBuilder<F>
+where
+    F: NetworkFilter + ?Sized,
+{
+    /// Calls `hook` with the outcome of every request, e.g. to update metrics.
+    ///
+    /// Without a hook no timing or event is recorded.
+    pub fn on_decision(
+        mut self,
+        hook: impl Fn(&FilterEvent<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.on_decision = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets what happens to requests without a client address, blocked by default.
+    pub fn on_missing_ip(mut self, policy: MissingIpPolicy) -> Self {
+        self.config.missing_ip = policy;
+        self
+    }
+
+    /// Answers requests without a client address with `factory`'s response,
+    /// status and body included, instead of the one picked by the
+    /// [`MissingIpPolicy`]. Not used when the policy is `Allow`.
+    pub fn missing_ip_response(
+        mut self,
+        factory: impl Fn() -> Response<Bytes> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.missing_ip_response = Some(Arc::new(factory));
+        self
+    }
+
+    /// Sets whether blocked requests are denied or only reported, enforced by default.
+    pub fn enforcement(mut self, enforcement: Enforcement) -> Self {
+        self.config.enforcement = enforcement;
+        self
+    }
+
+    /// Writes one line at `level` for every blocked request, for audit trails:
+    ///
+    /// ```text
+    /// denied GET /admin from 203.0.113.5: country France (FR), rule "country",
+    ///     mode BlackList, enforcement enforce
+    /// ```
+    ///
+    /// The mode is the filter's, see [`NetworkFilter::mode`]. In report-only
+    /// mode the line starts with `would deny`. Off by default.
+    pub fn log_denials(mut self, level: Level) -> Self {
+        self.config.denial_log = Some(level);
+        self
+    }
+
+    /// Sets the body format of denial responses, plain text by default.
+    pub fn response_format(mut self, format: ResponseFormat) -> Self {
+        self.config.response_format = format;
+        self
+    }
+
+    /// Passes requests for which `predicate` returns `true` straight to the
+    /// inner service, without checking the client address.
+    ///
+    /// Can be called repeatedly; a request skips the filter if any predicate matches.
+    pub fn skip_if(
+        mut self,
+        predicate: impl Fn(&http::request::Parts) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.config.bypass = Some(match self.config.bypass.take() {
+            Some(previous) => Arc::new(move |parts| previous(parts) || predicate(parts)),
+            None => Arc::new(predicate),
+        });
+        self
+    }
+
+    /// Skips the filter for requests below any of `prefixes`, e.g. `/healthz`
+    /// for health checks scraped from addresses that would otherwise be blocked.
+    ///
+    /// Prefixes match whole path segments: `/metrics` matches `/metrics` and
+    /// `/metrics/jobs` but not `/metricsfoo`.
+    pub fn skip_paths<I>(self, prefixes: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let prefixes: Vec<String> = prefixes.into_iter().map(Into::into).collect();
+        self.skip_if(move |parts| {
+            let path = parts.uri.path();
+            prefixes.iter().any(|prefix| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+                None => false,
+            })
+        })
+    }
+
+    /// The layer side of
+    /// [`GeoIpv4Filter::strict_whitelist`](crate::geo_filter::GeoIpv4Filter::strict_whitelist):
+    /// requests without a client address are blocked with
+    /// [`MissingIpPolicy::Block`] and blocked requests are denied with
+    /// [`Enforcement::Enforce`].
+    ///
+    /// Both are pinned, whatever [`FilterLayerBuilder::on_missing_ip`] and
+    /// [`FilterLayerBuilder::enforcement`] set before or after this. Paths
+    /// skipped with [`FilterLayerBuilder::skip_if`] are still skipped.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    pub fn build(mut self) -> FilterLayer<F> {
+        if self.strict {
+            self.config.missing_ip = MissingIpPolicy::Block;
+            self.config.enforcement = Enforcement::Enforce;
+        }
+        FilterLayer {
+            filter: self.filter,
+            config: self.config,
+        }
     }
 }
 
 impl<S, F> tower_layer::Layer<S> for FilterLayer<F>
 where
-    F: NetworkFilter,
+    F: NetworkFilter + ?Sized,
 {
     type Service = Filter<S, F>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        Filter::new(inner, self.filter.clone())
+        Filter {
+            inner,
+            filter: self.filter.clone(),
+            config: self.config.clone(),
+        }
     }
 }
 
+/// Adds [`ConnectionInfo`] to requests and filters on it, in one layer.
+///
+/// Stacking [`AddConnectionInfoLayer`] and [`FilterLayer`] by hand works too,
+/// but the connection info has to be added first or the filter sees no
+/// address. The standalone layers remain available for setups that need
+/// other middleware between the two.
+pub struct IpFilterLayer<F: ?Sized> {
+    connection_info: AddConnectionInfoLayer,
+    filter: FilterLayer<F>,
+}
 
+impl<F: ?Sized> Clone for IpFilterLayer<F> {
+    fn clone(&self) -> Self {
+        Self {
+            connection_info: self.connection_info.clone(),
+            filter: self.filter.clone(),
+        }
+    }
+}
 
+impl<F> IpFilterLayer<F>
+where
+    F: NetworkFilter + ?Sized,
+{
+    /// Combines a default [`AddConnectionInfoLayer`] with [`FilterLayer::new`].
+    pub fn new(filter: Arc<F>) -> Self {
+        Self::from_layers(AddConnectionInfoLayer::new(), FilterLayer::new(filter))
+    }
+
+    /// Combines layers configured separately, e.g. with trusted proxies or
+    /// through [`FilterLayer::builder`].
+    pub fn from_layers(connection_info: AddConnectionInfoLayer, filter: FilterLayer<F>) -> Self {
+        Self {
+            connection_info,
+            filter,
+        }
+    }
+}
+
+impl<S: Clone, F> tower_layer::Layer<S> for IpFilterLayer<F>
+where
+    F: NetworkFilter + ?Sized,
+{
+    type Service = AddConnectionInfo<Filter<S, F>>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        self.connection_info.layer(self.filter.layer(inner))
+    }
+}
 
-impl<S: Clone, ReqBody, ResBody, F: NetworkFilter + 'static> Service<Request<ReqBody>>
+impl<S: Clone, ReqBody, ResBody, F: NetworkFilter + ?Sized> Service<Request<ReqBody>>
     for Filter<S, F>
 where
     S: Service<Request<ReqBody>, Response = Response<ResBody>> + Send + 'static,
@@ -74,38 +617,349 @@ where
 {
     type Response = Response<IpResponseBody<ResBody>>;
     type Error = S::Error;
-    type Future = futures_lite::future::Boxed<Result<Self::Response, Self::Error>>;
+    type Future = ResponseFuture<S::Future, ResBody, S::Error>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.filter.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
         let ip_service = self.filter.clone();
+        let config = self.config.clone();
+        // Take the instance that was polled ready and leave a fresh clone behind,
+        // the clone may not have reserved capacity in the inner service.
         let inner = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, inner);
 
-        async move {
-            if let Some(ip) = req
-                .extensions()
-                .get::<ConnectionInfo>()
-                .map(|socket_addr| socket_addr.ip_addr)
-            {
-                if ip_service.is_blocked(ip).await {
-                    return Ok(ip_service.to_denied_response());
-                 
-                } else {
-                    return inner
+        if let Some(bypass) = &config.bypass {
+            let (parts, body) = req.into_parts();
+            let skip = bypass(&parts);
+            req = Request::from_parts(parts, body);
+            if skip {
+                return ResponseFuture::inner(inner.call(req), tracing::Span::none(), None);
+            }
+        }
+
+        let ip = req
+            .extensions()
+            .get::<ConnectionInfo>()
+            .map(|socket_addr| socket_addr.ip_addr);
+        // Covers the decision and the inner service, fields are recorded once known.
+        let span = tracing::info_span!(
+            "ip_filter",
+            ip = tracing::field::Empty,
+            blocked = tracing::field::Empty,
+            reason = tracing::field::Empty,
+            country = tracing::field::Empty,
+        );
+        let Some(ip) = ip else {
+            let mode = ip_service.mode();
+            let verdict = span.in_scope(|| missing_ip_verdict(&config, mode, &span, req));
+            return ResponseFuture::from_verdict(verdict, &mut inner, span);
+        };
+        span.record("ip", tracing::field::display(ip));
+
+        let timed = config.on_decision.is_some() || cfg!(feature = "metrics");
+        let started = timed.then(Instant::now);
+        span.in_scope(|| ip_service.record_request(ip));
+        if let Some(decision) = ip_service.decide_now(ip) {
+            let elapsed = started.map(|started| started.elapsed());
+            let verdict = span.in_scope(|| {
+                apply_decision(&*ip_service, &config, &span, ip, decision, elapsed, req)
+            });
+            return ResponseFuture::from_verdict(verdict, &mut inner, span);
+        }
+
+        // The filter has to wait for its decision, await it in a boxed future.
+        let future = async move {
+            let decision = ip_service.decide(ip).await;
+            let elapsed = started.map(|started| started.elapsed());
+            let span = tracing::Span::current();
+            match apply_decision(&*ip_service, &config, &span, ip, decision, elapsed, req) {
+                Verdict::Deny(res) => Ok(res),
+                Verdict::Forward(req, outcome) => inner
                     .call(req)
                     .await
-                    .map(|res| res.map(IpResponseBody::new));
-                }
-            } else {
-                tracing::warn!("No IP address found in request, blocking request");
-                return Ok(create_ip_not_found_response());
+                    .map(|res| outcome.attach(res.map(IpResponseBody::new))),
+            }
+        };
+        ResponseFuture {
+            state: ResponseState::Deciding {
+                future: future.instrument(span).boxed(),
+            },
+        }
+    }
+}
+
+/// What [`Filter`] does with a request once it is decided.
+enum Verdict<ReqBody, ResBody> {
+    Deny(Response<IpResponseBody<ResBody>>),
+    Forward(Request<ReqBody>, FilterOutcome),
+}
+
+/// Records `decision` for `ip`, then denies the request if it is blocked and
+/// enforced, otherwise forwards it with the resolved country attached.
+fn apply_decision<F, ReqBody, ResBody>(
+    filter: &F,
+    config: &FilterConfig,
+    span: &tracing::Span,
+    ip: IpAddr,
+    decision: FilterDecision,
+    elapsed: Option<Duration>,
+    mut req: Request<ReqBody>,
+) -> Verdict<ReqBody, ResBody>
+where
+    F: NetworkFilter + ?Sized,
+    ResBody: Body,
+{
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_decision(&decision, elapsed);
+    span.record("blocked", decision.blocked);
+    if let Some(reason) = &decision.reason {
+        span.record("reason", reason.as_str());
+    }
+    if let Some(country) = &decision.country {
+        span.record("country", country.country_name.as_deref());
+    }
+    if let (Some(hook), Some(elapsed)) = (&config.on_decision, elapsed) {
+        hook(&FilterEvent {
+            ip: Some(ip),
+            decision: &decision,
+            elapsed,
+        });
+    }
+    if let (true, Some(level)) = (decision.blocked, config.denial_log) {
+        let line = denial_line(Some(ip), &decision, filter.mode(), config, &req);
+        log_denial(level, &line);
+    }
+    let outcome = FilterOutcome {
+        ip: Some(ip),
+        decision: decision.clone(),
+    };
+    if decision.blocked && config.enforcement == Enforcement::Enforce {
+        tracing::debug!("Request blocked");
+        let res = match config.response_format {
+            ResponseFormat::PlainText => filter.to_denied_response(),
+            ResponseFormat::Json => json_denied_response(&decision),
+        };
+        let res = match decision.expires {
+            Some(expires) => with_retry_after(res, expires),
+            None => res,
+        };
+        return Verdict::Deny(outcome.attach(res));
+    }
+    if decision.blocked {
+        tracing::info!("Request would be blocked, forwarding in report-only mode");
+    }
+    if let (Some(country), Some(info)) = (
+        decision.country,
+        req.extensions_mut().get_mut::<ConnectionInfo>(),
+    ) {
+        info.country = Some(country);
+    }
+    Verdict::Forward(req, outcome)
+}
+
+/// Decides on a request without a client address, see [`MissingIpPolicy`].
+fn missing_ip_verdict<ReqBody, ResBody: Body>(
+    config: &FilterConfig,
+    mode: Option<Mode>,
+    span: &tracing::Span,
+    req: Request<ReqBody>,
+) -> Verdict<ReqBody, ResBody> {
+    let blocked = config.missing_ip != MissingIpPolicy::Allow;
+    span.record("blocked", blocked);
+    span.record("reason", "no client IP");
+    let decision = FilterDecision::new(blocked).with_reason("no client IP");
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_decision(&decision, None);
+    if let Some(hook) = &config.on_decision {
+        hook(&FilterEvent {
+            ip: None,
+            decision: &decision,
+            elapsed: Duration::ZERO,
+        });
+    }
+    if let (true, Some(level)) = (blocked, config.denial_log) {
+        log_denial(level, &denial_line(None, &decision, mode, config, &req));
+    }
+    let outcome = FilterOutcome { ip: None, decision };
+    if blocked && config.enforcement == Enforcement::Enforce {
+        tracing::warn!("No IP address found in request, blocking request");
+        Verdict::Deny(outcome.attach(missing_ip_response(config)))
+    } else {
+        tracing::debug!("No IP address found in request, forwarding request");
+        Verdict::Forward(req, outcome)
+    }
+}
+
+pin_project! {
+    /// The response future of [`Filter`].
+    ///
+    /// Filters answering [`NetworkFilter::decide_now`] are decided in `call`, so
+    /// this is either the inner service's future or a ready denial, without an
+    /// allocation per request. Dropping it, e.g. from a timeout layer, cancels
+    /// the inner call.
+    pub struct ResponseFuture<Fut, B, E> {
+        #[pin]
+        state: ResponseState<Fut, B, E>,
+    }
+}
+
+pin_project! {
+    #[project = ResponseStateProj]
+    enum ResponseState<Fut, B, E> {
+        Inner {
+            #[pin]
+            future: Instrumented<Fut>,
+            // Added to the response, `None` for requests that skip the filter.
+            outcome: Option<FilterOutcome>,
+        },
+        Denied {
+            future: Ready<Result<Response<IpResponseBody<B>>, E>>,
+        },
+        // Filters that wait for their decision, see `NetworkFilter::decide_now`.
+        Deciding {
+            future: Boxed<Result<Response<IpResponseBody<B>>, E>>,
+        },
+    }
+}
+
+impl<Fut, B, E> ResponseFuture<Fut, B, E> {
+    fn inner(future: Fut, span: tracing::Span, outcome: Option<FilterOutcome>) -> Self {
+        Self {
+            state: ResponseState::Inner {
+                future: future.instrument(span),
+                outcome,
+            },
+        }
+    }
+
+    fn from_verdict<ReqBody, S>(
+        verdict: Verdict<ReqBody, B>,
+        inner: &mut S,
+        span: tracing::Span,
+    ) -> Self
+    where
+        S: Service<Request<ReqBody>, Future = Fut, Error = E>,
+    {
+        match verdict {
+            Verdict::Deny(res) => Self {
+                state: ResponseState::Denied {
+                    future: std::future::ready(Ok(res)),
+                },
+            },
+            Verdict::Forward(req, outcome) => {
+                let future = span.in_scope(|| inner.call(req));
+                Self::inner(future, span, Some(outcome))
             }
         }
-        .boxed()
+    }
+}
+
+impl<Fut, B, E> Future for ResponseFuture<Fut, B, E>
+where
+    Fut: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<IpResponseBody<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project().state.project() {
+            ResponseStateProj::Inner { future, outcome } => future.poll(cx).map(|result| {
+                result.map(|res| {
+                    let res = res.map(IpResponseBody::new);
+                    match outcome.take() {
+                        Some(outcome) => outcome.attach(res),
+                        None => res,
+                    }
+                })
+            }),
+            ResponseStateProj::Denied { future } => Pin::new(future).poll(cx),
+            ResponseStateProj::Deciding { future } => future.as_mut().poll(cx),
+        }
+    }
+}
+
+// Servers spawn the response future, so it must be `Send` whenever the inner future is.
+const _: fn() = || {
+    type Inner = Ready<Result<Response<NoBody>, std::convert::Infallible>>;
+    fn assert_send<T: Send>() {}
+    assert_send::<ResponseFuture<Inner, NoBody, std::convert::Infallible>>();
+};
+
+/// The response for a request without a client address under a denying policy.
+fn missing_ip_response<B: Body>(config: &FilterConfig) -> Response<IpResponseBody<B>> {
+    if let Some(factory) = &config.missing_ip_response {
+        return factory().map(IpResponseBody::from_bytes);
+    }
+    match (config.missing_ip, config.response_format) {
+        (MissingIpPolicy::Reject, ResponseFormat::PlainText) => create_ip_not_identified_response(),
+        (MissingIpPolicy::Reject, ResponseFormat::Json) => create_ip_not_identified_response_json(),
+        (_, ResponseFormat::PlainText) => create_ip_not_found_response(),
+        (_, ResponseFormat::Json) => create_ip_not_found_response_json(),
+    }
+}
+
+/// The line written by [`FilterLayerBuilder::log_denials`].
+fn denial_line<B>(
+    ip: Option<IpAddr>,
+    decision: &FilterDecision,
+    mode: Option<Mode>,
+    config: &FilterConfig,
+    req: &Request<B>,
+) -> String {
+    let (verb, enforcement) = match config.enforcement {
+        Enforcement::Enforce => ("denied", "enforce"),
+        Enforcement::ReportOnly => ("would deny", "report-only"),
+    };
+    let ip = ip.map_or_else(|| "unknown address".to_string(), |ip| ip.to_string());
+    let country = match &decision.country {
+        Some(country) => format!(
+            "{} ({})",
+            country.country_name.as_deref().unwrap_or("unknown"),
+            country.country_iso_code.as_deref().unwrap_or("-"),
+        ),
+        None => "unknown".to_string(),
+    };
+    format!(
+        "{} {} {} from {}: country {}, rule {:?}, mode {}, enforcement {}",
+        verb,
+        req.method(),
+        req.uri().path(),
+        ip,
+        country,
+        decision.reason.as_deref().unwrap_or("none"),
+        mode.map_or_else(|| "unknown".to_string(), |mode| mode.to_string()),
+        enforcement,
+    )
+}
+
+/// `tracing` needs the level at compile time, so pick the macro at runtime.
+fn log_denial(level: Level, line: &str) {
+    match level {
+        Level::ERROR => tracing::error!("{}", line),
+        Level::WARN => tracing::warn!("{}", line),
+        Level::INFO => tracing::info!("{}", line),
+        Level::DEBUG => tracing::debug!("{}", line),
+        _ => tracing::trace!("{}", line),
+    }
+}
+
+/// Describes a blocked decision as JSON: by country code when the address
+/// resolved to a country, otherwise by the rule that matched it.
+fn json_denied_response<B: Body>(decision: &FilterDecision) -> Response<IpResponseBody<B>> {
+    match &decision.country {
+        Some(country) => create_access_denied_response_json(
+            "country",
+            country
+                .country_iso_code
+                .as_deref()
+                .or(country.country_name.as_deref()),
+        ),
+        None => create_access_denied_response_json("ip", decision.reason.as_deref()),
     }
 }
 
@@ -115,12 +969,16 @@ pub fn filter<F: NetworkFilter>(filter: F) -> FilterLayer<F> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{geo_filter::GeoIpv4Filter, types::CountryLocation};
+    use crate::{
+        connection_info_service::AddConnectionInfoLayer, geo_filter::GeoIpv4Filter,
+        types::CountryLocation,
+    };
 
     use super::*;
 
     use axum::{
         body::Body,
+        extract::ConnectInfo,
         http::{Request, StatusCode},
         response::IntoResponse,
         routing::get,
@@ -169,12 +1027,7 @@ mod tests {
             },
         );
 
-        GeoIpv4Filter {
-            networks: ip_country_map,
-            addresses: DashMap::new(),
-            countries: DashMap::new(),
-            mode: Default::default(),
-        }
+        GeoIpv4Filter::from_networks(Default::default(), ip_country_map)
     }
 
     fn create_app(geo_service: GeoIpv4Filter) -> Router {
@@ -182,6 +1035,7 @@ mod tests {
             .route("/", get(handler))
             .layer(TraceLayer::new_for_http())
             .layer(filter(geo_service))
+            .layer(AddConnectionInfoLayer::new())
     }
 
     #[tokio::test]
@@ -255,7 +1109,13 @@ mod tests {
             .await
             .unwrap();
 
-        assert_eq!(response.status(), StatusCode::OK);
+        // Without a peer address or forwarding header there is no country to
+        // check; the request is denied by the default MissingIpPolicy::Block,
+        // not by the country list.
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let outcome = response.extensions().get::<FilterOutcome>().unwrap();
+        assert_eq!(outcome.ip, None);
+        assert_eq!(outcome.decision.reason.as_deref(), Some("no client IP"));
     }
 
     #[tokio::test]
@@ -374,7 +1234,7 @@ mod tests {
 
         let allowed_request = Request::builder()
             .uri("/")
-            .extension(SocketAddr::from_str("192.168.1.1:12345").unwrap())
+            .extension(ConnectInfo(SocketAddr::from_str("192.168.1.1:12345").unwrap()))
             .body(Body::empty())
             .unwrap();
         assert_eq!(
@@ -384,7 +1244,7 @@ mod tests {
 
         let blocked_request = Request::builder()
             .uri("/")
-            .extension(SocketAddr::from_str("10.0.0.1:12345").unwrap())
+            .extension(ConnectInfo(SocketAddr::from_str("10.0.0.1:12345").unwrap()))
             .body(Body::empty())
             .unwrap();
         assert_eq!(
@@ -429,7 +1289,635 @@ mod tests {
         geo_service.set_countries(vec!["United States".to_string()]);
         let app = create_app(geo_service);
 
+        // Denied for lacking an address, see test_geo_ip_filter_no_ip_header.
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        assert_eq!(test_request(app.clone(), request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_dyn_network_filters() {
+        use crate::ip_filter::DualIpFilter;
+        use std::net::Ipv4Addr;
+
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+        let ip_service = DualIpFilter::new(Mode::BlackList);
+        ip_service.block(Ipv4Addr::new(192, 168, 1, 1), false).await;
+
+        let filters: Vec<Arc<dyn DynNetworkFilter>> =
+            vec![Arc::new(geo_service), Arc::new(ip_service)];
+        let us: IpAddr = "10.0.0.1".parse().unwrap();
+        let uk: IpAddr = "192.168.1.1".parse().unwrap();
+
+        assert!(filters[0].is_blocked(us).await);
+        assert!(!filters[0].is_blocked(uk).await);
+        assert!(!filters[1].is_blocked(us).await);
+        assert!(filters[1].is_blocked(uk).await);
+
+        for (filter, blocked) in filters.into_iter().zip(["10.0.0.1", "192.168.1.1"]) {
+            let app = Router::new()
+                .route("/", get(handler))
+                .layer(FilterLayer::new(filter))
+                .layer(AddConnectionInfoLayer::new());
+
+            let request = Request::builder()
+                .uri("/")
+                .header("X-Forwarded-For", blocked)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(test_request(app, request).await, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_info_carries_country() {
+        use http_body_util::BodyExt;
+
+        async fn country_handler(req: Request<Body>) -> String {
+            req.extensions()
+                .get::<ConnectionInfo>()
+                .and_then(|info| info.country.as_ref())
+                .and_then(|country| country.country_iso_code.clone())
+                .unwrap_or_default()
+        }
+
+        let geo_service = create_test_geo_ip_service();
+        let app = Router::new()
+            .route("/", get(country_handler))
+            .layer(filter(geo_service))
+            .layer(AddConnectionInfoLayer::new());
+
+        let request = Request::builder()
+            .uri("/")
+            .header("X-Forwarded-For", "192.168.1.1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"GB");
+    }
+
+    #[tokio::test]
+    async fn test_on_decision_hook() {
+        use std::sync::Mutex;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(
+                FilterLayer::builder(Arc::new(geo_service))
+                    .on_decision(move |event| {
+                        recorded.lock().unwrap().push((
+                            event.ip,
+                            event.decision.blocked,
+                            event.decision.reason.clone(),
+                        ));
+                    })
+                    .build(),
+            )
+            .layer(AddConnectionInfoLayer::new());
+
+        for ip in ["10.0.0.1", "192.168.1.1"] {
+            let request = Request::builder()
+                .uri("/")
+                .header("X-Forwarded-For", ip)
+                .body(Body::empty())
+                .unwrap();
+            test_request(app.clone(), request).await;
+        }
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        test_request(app, request).await;
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            *events,
+            vec![
+                (
+                    Some("10.0.0.1".parse().unwrap()),
+                    true,
+                    Some("country United States".to_string())
+                ),
+                (Some("192.168.1.1".parse().unwrap()), false, None),
+                (None, true, Some("no client IP".to_string())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_skip_paths() {
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+        let app = Router::new()
+            .route("/", get(handler))
+            .route("/healthz", get(handler))
+            .route("/healthzz", get(handler))
+            .route("/metrics/jobs", get(handler))
+            .route("/internal", get(handler))
+            .layer(
+                FilterLayer::builder(Arc::new(geo_service))
+                    .skip_paths(["/healthz", "/metrics"])
+                    .skip_if(|parts| parts.headers.contains_key("x-monitor"))
+                    .build(),
+            )
+            .layer(AddConnectionInfoLayer::new());
+
+        let status = |uri: &str, monitor: bool| {
+            let mut request = Request::builder()
+                .uri(uri)
+                .header("X-Forwarded-For", "10.0.0.1");
+            if monitor {
+                request = request.header("x-monitor", "1");
+            }
+            test_request(app.clone(), request.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(status("/healthz", false).await, StatusCode::OK);
+        assert_eq!(status("/metrics/jobs", false).await, StatusCode::OK);
+        assert_eq!(status("/internal", true).await, StatusCode::OK);
+        assert_eq!(status("/healthzz", false).await, StatusCode::FORBIDDEN);
+        assert_eq!(status("/", false).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_poll_ready_waits_for_reload() {
+        let geo_service = create_test_geo_ip_service();
+        let mut service = Filter::new(
+            tower::service_fn(|_: Request<Body>| async {
+                Ok::<_, std::convert::Infallible>(Response::new(Body::empty()))
+            }),
+            Arc::new(geo_service.clone()),
+        );
+
+        let update = geo_service.reload_gate.begin();
+        let waiting = tokio::spawn(async move { service.ready().await.map(|_| ()) });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        drop(update);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    /// Only serves requests on the instance that was polled ready, like services
+    /// holding a reservation such as `tower::buffer::Buffer`.
+    #[derive(Default)]
+    struct ReadyTracked {
+        ready: bool,
+        calls: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Clone for ReadyTracked {
+        fn clone(&self) -> Self {
+            Self {
+                ready: false,
+                calls: self.calls.clone(),
+            }
+        }
+    }
+
+    impl Service<Request<Body>> for ReadyTracked {
+        type Response = Response<Body>;
+        type Error = std::convert::Infallible;
+        type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.ready = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<Body>) -> Self::Future {
+            assert!(self.ready, "call without poll_ready on the same instance");
+            self.ready = false;
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            std::future::ready(Ok(Response::new(Body::empty())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_uses_readied_instance() {
+        let inner = ReadyTracked::default();
+        let calls = inner.calls.clone();
+        let mut service = Filter::new(inner, Arc::new(create_test_geo_ip_service()));
+        let request = || {
+            let mut request = Request::new(Body::empty());
+            request.extensions_mut().insert(ConnectionInfo {
+                ip_addr: "10.0.0.1".parse().unwrap(),
+                country: None,
+            });
+            request
+        };
+
+        for _ in 0..3 {
+            let response = service.ready().await.unwrap().call(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // A clone taken after readying starts out unready and must be polled itself.
+        service.ready().await.unwrap();
+        let mut clone = service.clone();
+        service.call(request()).await.unwrap();
+        clone.ready().await.unwrap().call(request()).await.unwrap();
+
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 5);
+    }
+
+    /// Yields once before deciding, like a filter backed by a remote store.
+    struct Waiting(GeoIpv4Filter);
+
+    impl NetworkFilter for Waiting {
+        async fn block(&self, ip: impl IpAddrExt, network: bool) {
+            self.0.block(ip, network).await
+        }
+
+        async fn unblock(&self, ip: impl IpAddrExt, network: bool) {
+            self.0.unblock(ip, network).await
+        }
+
+        async fn is_blocked(&self, ip: impl IpAddrExt) -> bool {
+            self.decide(ip).await.blocked
+        }
+
+        async fn decide(&self, ip: impl IpAddrExt) -> FilterDecision {
+            futures_lite::future::yield_now().await;
+            self.0.decide(ip).await
+        }
+
+        fn to_denied_response<T: http_body::Body>(&self) -> http::Response<IpResponseBody<T>> {
+            self.0.to_denied_response()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_future_states() {
+        let request = |ip: &str| {
+            let mut request = Request::new(Body::empty());
+            request.extensions_mut().insert(ConnectionInfo {
+                ip_addr: ip.parse().unwrap(),
+                country: None,
+            });
+            request
+        };
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+
+        let mut service = Filter::new(ReadyTracked::default(), Arc::new(geo_service.clone()));
+        service.ready().await.unwrap();
+        let denied = service.call(request("10.0.0.1"));
+        assert!(matches!(denied.state, ResponseState::Denied { .. }));
+        assert_eq!(denied.await.unwrap().status(), StatusCode::FORBIDDEN);
+        service.ready().await.unwrap();
+        let allowed = service.call(request("192.168.1.1"));
+        assert!(matches!(allowed.state, ResponseState::Inner { .. }));
+        assert_eq!(allowed.await.unwrap().status(), StatusCode::OK);
+
+        let mut service = Filter::new(ReadyTracked::default(), Arc::new(Waiting(geo_service)));
+        service.ready().await.unwrap();
+        let denied = service.call(request("10.0.0.1"));
+        assert!(matches!(denied.state, ResponseState::Deciding { .. }));
+        assert_eq!(denied.await.unwrap().status(), StatusCode::FORBIDDEN);
+        service.ready().await.unwrap();
+        let allowed = service.call(request("192.168.1.1"));
+        assert_eq!(allowed.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_filter_outcome_extension() {
+        let request = |ip: Option<&str>| {
+            let mut request = Request::new(Body::empty());
+            if let Some(ip) = ip {
+                request.extensions_mut().insert(ConnectionInfo {
+                    ip_addr: ip.parse().unwrap(),
+                    country: None,
+                });
+            }
+            request
+        };
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+        let outcome = |res: Response<IpResponseBody<Body>>| {
+            let outcome = res.extensions().get::<FilterOutcome>().unwrap();
+            (outcome.ip.map(|ip| ip.to_string()), outcome.decision.blocked)
+        };
+
+        let mut service = Filter::new(ReadyTracked::default(), Arc::new(geo_service.clone()));
+        let res = service.ready().await.unwrap().call(request(Some("10.0.0.1"))).await;
+        assert_eq!(outcome(res.unwrap()), (Some("10.0.0.1".to_string()), true));
+        let res = service.ready().await.unwrap().call(request(Some("192.168.1.1"))).await;
+        let res = res.unwrap();
+        let decision = &res.extensions().get::<FilterOutcome>().unwrap().decision;
+        let country = decision.country.as_ref().unwrap();
+        assert_eq!(country.country_name.as_deref(), Some("United Kingdom"));
+        assert_eq!(outcome(res), (Some("192.168.1.1".to_string()), false));
+        let res = service.ready().await.unwrap().call(request(None)).await;
+        assert_eq!(outcome(res.unwrap()), (None, true));
+
+        let mut service = Filter::new(ReadyTracked::default(), Arc::new(Waiting(geo_service)));
+        let res = service.ready().await.unwrap().call(request(Some("10.0.0.1"))).await;
+        assert_eq!(outcome(res.unwrap()), (Some("10.0.0.1".to_string()), true));
+        let res = service.ready().await.unwrap().call(request(Some("192.168.1.1"))).await;
+        assert_eq!(outcome(res.unwrap()), (Some("192.168.1.1".to_string()), false));
+    }
+
+    #[tokio::test]
+    async fn test_temporary_block_returns_retry_after() {
+        let ip_filter = crate::ip_filter::DualIpFilter::new(crate::types::Mode::BlackList);
+        ip_filter
+            .add_ip_for(
+                "10.0.0.1".parse().unwrap(),
+                "Abuse".to_string(),
+                "2024-01-10".parse().unwrap(),
+                Duration::from_secs(3600),
+            )
+            .await;
+        ip_filter
+            .add_ip("10.0.0.2".parse().unwrap(), "Abuse".to_string(), "2024-01-10".parse().unwrap())
+            .await;
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(filter(ip_filter))
+            .layer(AddConnectionInfoLayer::new());
+
+        let send = |ip: &str| {
+            let request = Request::builder()
+                .uri("/")
+                .header("X-Forwarded-For", ip)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let res = send("10.0.0.1").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = res.headers()[http::header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((3599..=3600).contains(&retry_after));
+
+        let res = send("10.0.0.2").await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(!res.headers().contains_key(http::header::RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn test_missing_ip_allowed() {
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(
+                FilterLayer::builder(Arc::new(geo_service))
+                    .on_missing_ip(MissingIpPolicy::Allow)
+                    .build(),
+            )
+            .layer(AddConnectionInfoLayer::new());
+
+        let request = Request::builder()
+            .uri("/")
+            .header("X-Forwarded-For", "10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(test_request(app.clone(), request).await, StatusCode::FORBIDDEN);
+
         let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        assert_eq!(test_request(app, request).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_strict_layer() {
+        let filter = Arc::new(
+            create_test_geo_ip_service().strict_whitelist(vec!["United Kingdom".to_string()]),
+        );
+        let layers = [
+            FilterLayer::builder(filter.clone())
+                .on_missing_ip(MissingIpPolicy::Allow)
+                .strict()
+                .build(),
+            FilterLayer::builder(filter)
+                .strict()
+                .on_missing_ip(MissingIpPolicy::Allow)
+                .enforcement(Enforcement::ReportOnly)
+                .build(),
+        ];
+        for layer in layers {
+            let app = Router::new()
+                .route("/", get(handler))
+                .layer(layer)
+                .layer(AddConnectionInfoLayer::new());
+            let request = |ip: Option<&str>| {
+                let request = Request::builder().uri("/");
+                match ip {
+                    Some(ip) => request.header("X-Forwarded-For", ip),
+                    None => request,
+                }
+                .body(Body::empty())
+                .unwrap()
+            };
+            let status = |ip| test_request(app.clone(), request(ip));
+            assert_eq!(status(Some("192.168.1.1")).await, StatusCode::OK);
+            assert_eq!(status(Some("10.0.0.1")).await, StatusCode::FORBIDDEN);
+            assert_eq!(status(Some("203.0.113.1")).await, StatusCode::FORBIDDEN);
+            assert_eq!(status(None).await, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_ip_rejected_with_own_response() {
+        use http_body_util::BodyExt;
+
+        let app = |layer: FilterLayer<GeoIpv4Filter>| {
+            Router::new()
+                .route("/", get(handler))
+                .layer(layer)
+                .layer(AddConnectionInfoLayer::new())
+        };
+        let request = || Request::builder().uri("/").body(Body::empty()).unwrap();
+
+        let rejecting = FilterLayer::builder(Arc::new(create_test_geo_ip_service()))
+            .on_missing_ip(MissingIpPolicy::Reject)
+            .build();
+        assert_eq!(test_request(app(rejecting), request()).await, StatusCode::BAD_REQUEST);
+
+        let custom = FilterLayer::builder(Arc::new(create_test_geo_ip_service()))
+            .on_missing_ip(MissingIpPolicy::Reject)
+            .missing_ip_response(|| {
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Bytes::from_static(b"who are you?"))
+                    .unwrap()
+            })
+            .build();
+        let response = app(custom).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"who are you?");
+
+        let allowing = FilterLayer::builder(Arc::new(create_test_geo_ip_service()))
+            .on_missing_ip(MissingIpPolicy::Allow)
+            .missing_ip_response(|| Response::new(Bytes::new()))
+            .build();
+        assert_eq!(test_request(app(allowing), request()).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_ip_filter_layer() {
+        use axum::Extension;
+        use http_body_util::BodyExt;
+
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(info): Extension<ConnectionInfo>| async move {
+                    info.ip_addr.to_string()
+                }),
+            )
+            .layer(IpFilterLayer::new(Arc::new(geo_service)));
+        let request = |ip: &str| {
+            Request::builder()
+                .uri("/")
+                .header("X-Forwarded-For", ip)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("10.0.0.1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = app.oneshot(request("192.168.1.1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"192.168.1.1");
+    }
+
+    #[tokio::test]
+    async fn test_auto_ban_counts_requests() {
+        let ip_filter = crate::ip_filter::DualIpFilter::new(Default::default())
+            .enable_auto_ban(2, Duration::from_secs(60), Duration::from_secs(600));
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(IpFilterLayer::new(Arc::new(ip_filter)));
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header("X-Forwarded-For", "203.0.113.5")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(test_request(app.clone(), request()).await, StatusCode::OK);
+        assert_eq!(test_request(app.clone(), request()).await, StatusCode::OK);
+        assert_eq!(test_request(app, request()).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_denial_line() {
+        let request = Request::post("/admin/users?page=2").body(()).unwrap();
+        let decision = FilterDecision::new(true)
+            .with_reason("country")
+            .with_country(CountryLocation {
+                geoname_id: 1,
+                locale_code: "en".to_string(),
+                continent_code: "EU".to_string(),
+                continent_name: "Europe".to_string(),
+                country_iso_code: Some("FR".to_string()),
+                country_name: Some("France".to_string()),
+                is_in_european_union: true,
+            });
+        let mut config = FilterConfig::default();
+        assert_eq!(
+            denial_line(
+                Some("203.0.113.5".parse().unwrap()),
+                &decision,
+                Some(Mode::BlackList),
+                &config,
+                &request
+            ),
+            "denied POST /admin/users from 203.0.113.5: country France (FR), rule \"country\", \
+             mode BlackList, enforcement enforce"
+        );
+
+        config.enforcement = Enforcement::ReportOnly;
+        let decision = FilterDecision::new(true).with_reason("no client IP");
+        assert_eq!(
+            denial_line(None, &decision, None, &config, &request),
+            "would deny POST /admin/users from unknown address: country unknown, \
+             rule \"no client IP\", mode unknown, enforcement report-only"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_only_forwards_and_reports() {
+        let blocked = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = blocked.clone();
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(
+                FilterLayer::builder(Arc::new(geo_service))
+                    .enforcement(Enforcement::ReportOnly)
+                    .on_decision(move |event| {
+                        if event.decision.blocked {
+                            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        }
+                    })
+                    .build(),
+            )
+            .layer(AddConnectionInfoLayer::new());
+
+        let request = Request::builder()
+            .uri("/")
+            .header("X-Forwarded-For", "10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
         assert_eq!(test_request(app.clone(), request).await, StatusCode::OK);
+        // A missing IP counts as blocked under the default policy, but isn't enforced either.
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        assert_eq!(test_request(app, request).await, StatusCode::OK);
+        assert_eq!(blocked.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_json_response_format() {
+        use http_body_util::BodyExt;
+
+        let geo_service = create_test_geo_ip_service();
+        geo_service.set_countries(vec!["United States".to_string()]);
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(
+                FilterLayer::builder(Arc::new(geo_service))
+                    .response_format(ResponseFormat::Json)
+                    .build(),
+            )
+            .layer(AddConnectionInfoLayer::new());
+
+        let request = Request::builder()
+            .uri("/")
+            .header("X-Forwarded-For", "10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.headers()[http::header::CONTENT_TYPE], "application/json");
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["reason"], "country");
+        assert_eq!(body["detail"], "US");
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let res = app.oneshot(request).await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], br#"{"error":"ip_not_found"}"#);
     }
 }
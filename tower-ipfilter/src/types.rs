@@ -1,6 +1,13 @@
 use std::collections::HashMap;
 
-use bincode::{Decode, Encode};
+use bincode::{
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+    Decode, Encode,
+};
+use dashmap::DashMap;
+use ipnetwork::{Ipv4Network, Ipv6Network};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize, Encode, Decode, PartialEq)]
@@ -47,18 +54,78 @@ pub struct GeoData {
     pub country_locations: HashMap<u32, CountryLocation>,
 }
 
-#[derive(Debug, Clone)]
-pub enum Mode {
-    BlackList,
-    WhiteList,
+/// Networks already resolved to their country, as built from the CSV data and
+/// stored in the cache.
+///
+/// Encoded entry by entry so both parsing and loading the cache insert
+/// straight into the map without an intermediate `Vec`. The IPv6 networks are
+/// kept even though only the IPv4 filter uses them so far, so the cache stays
+/// complete for the source.
+#[derive(Debug, Default)]
+pub(crate) struct CountryNetworks {
+    pub(crate) v4: DashMap<Ipv4Network, CountryLocation>,
+    pub(crate) v6: DashMap<Ipv6Network, CountryLocation>,
+}
+
+impl Encode for CountryNetworks {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        (self.v4.len() as u64).encode(encoder)?;
+        for entry in self.v4.iter() {
+            u32::from(entry.key().ip()).encode(encoder)?;
+            entry.key().prefix().encode(encoder)?;
+            entry.value().encode(encoder)?;
+        }
+        (self.v6.len() as u64).encode(encoder)?;
+        for entry in self.v6.iter() {
+            u128::from(entry.key().ip()).encode(encoder)?;
+            entry.key().prefix().encode(encoder)?;
+            entry.value().encode(encoder)?;
+        }
+        Ok(())
+    }
 }
 
-impl Default for Mode {
-    fn default() -> Self {
-        Mode::BlackList
+impl<Context> Decode<Context> for CountryNetworks {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let invalid = |err: ipnetwork::IpNetworkError| DecodeError::OtherString(err.to_string());
+
+        let v4 = DashMap::new();
+        for _ in 0..u64::decode(decoder)? {
+            let ip = u32::decode(decoder)?;
+            let prefix = u8::decode(decoder)?;
+            let network = Ipv4Network::new(ip.into(), prefix).map_err(invalid)?;
+            v4.insert(network, CountryLocation::decode(decoder)?);
+        }
+        let v6 = DashMap::new();
+        for _ in 0..u64::decode(decoder)? {
+            let ip = u128::decode(decoder)?;
+            let prefix = u8::decode(decoder)?;
+            let network = Ipv6Network::new(ip.into(), prefix).map_err(invalid)?;
+            v6.insert(network, CountryLocation::decode(decoder)?);
+        }
+        Ok(Self { v4, v6 })
     }
 }
 
+#[derive(Debug, Deserialize, Serialize, Encode, Decode, PartialEq)]
+pub struct AsnBlock {
+    pub network: String,
+    pub autonomous_system_number: Option<u32>,
+    pub autonomous_system_organization: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Encode, Decode)]
+pub struct AsnData {
+    pub blocks: Vec<AsnBlock>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    #[default]
+    BlackList,
+    WhiteList,
+}
+
 impl std::fmt::Display for Mode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {